@@ -0,0 +1,580 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libnspire::info::Info;
+use rusb::GlobalContext;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::crypt;
+use crate::tree::{join, TreeEntry};
+
+/// Name of the manifest inside a backup archive.
+pub const MANIFEST: &str = "manifest.json";
+/// Directory inside a backup archive holding the calculator's files.
+pub const FILES_DIR: &str = "files";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+  /// Path relative to the backup root.
+  pub path: String,
+  pub is_dir: bool,
+  pub size: u64,
+  pub date: u64,
+  /// Hex-encoded SHA-256 of the file contents.
+  pub sha256: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SkippedEntry {
+  pub path: String,
+  pub error: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+  /// The ID ("serial number") of the calculator.
+  pub device_id: String,
+  pub device_name: String,
+  pub hw_type: String,
+  pub os_version: String,
+  /// When the backup was made, in seconds since the Unix epoch.
+  pub created: u64,
+  /// The calculator folder that was backed up.
+  pub root: String,
+  pub files: Vec<ManifestEntry>,
+  /// Files that could not be read from the calculator.
+  #[serde(default)]
+  pub skipped: Vec<SkippedEntry>,
+}
+
+impl Manifest {
+  pub fn new(info: &Info, root: &str) -> Self {
+    Manifest {
+      device_id: info.id.clone(),
+      device_name: info.name.clone(),
+      hw_type: format!("{:?}", info.hw_type),
+      os_version: info.version.to_string(),
+      created: now(),
+      root: root.to_string(),
+      files: vec![],
+      skipped: vec![],
+    }
+  }
+}
+
+pub fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |time| time.as_secs())
+}
+
+pub fn sha256(data: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(data))
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+  Zip,
+  Tar,
+}
+
+impl ArchiveFormat {
+  pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+    match path
+      .extension()
+      .map(|ext| ext.to_string_lossy().to_lowercase())
+      .as_deref()
+    {
+      Some("zip") => Ok(ArchiveFormat::Zip),
+      Some("tar") => Ok(ArchiveFormat::Tar),
+      _ => anyhow::bail!("Unsupported archive type: use a .zip or .tar file"),
+    }
+  }
+}
+
+/// Splits a Unix timestamp into UTC year, month, day, hour, minute and second.
+pub fn civil_time(secs: u64) -> (u16, u8, u8, u8, u8, u8) {
+  let days = (secs / 86400) as i64;
+  let rem = secs % 86400;
+  // Days to civil date, from Howard Hinnant's `civil_from_days`.
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+  let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+  (
+    year,
+    month,
+    day,
+    (rem / 3600) as u8,
+    (rem / 60 % 60) as u8,
+    (rem % 60) as u8,
+  )
+}
+
+/// Formats a Unix timestamp as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn format_time(secs: u64) -> String {
+  let (year, month, day, hour, minute, second) = civil_time(secs);
+  format!(
+    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+    year, month, day, hour, minute, second
+  )
+}
+
+/// Converts a Unix timestamp to the MS-DOS time stored in zip files.
+fn zip_time(secs: u64) -> zip::DateTime {
+  let (year, month, day, hour, minute, second) = civil_time(secs);
+  zip::DateTime::from_date_and_time(year, month, day, hour, minute, second).unwrap_or_default()
+}
+
+/// Writes entries to a zip or tar archive.
+pub enum ArchiveWriter<W: Write + Seek> {
+  Zip(ZipWriter<W>),
+  Tar(tar::Builder<W>),
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+  pub fn new(format: ArchiveFormat, inner: W) -> Self {
+    match format {
+      ArchiveFormat::Zip => ArchiveWriter::Zip(ZipWriter::new(inner)),
+      ArchiveFormat::Tar => ArchiveWriter::Tar(tar::Builder::new(inner)),
+    }
+  }
+
+  pub fn add_dir(&mut self, path: &str, date: u64) -> anyhow::Result<()> {
+    match self {
+      ArchiveWriter::Zip(zip) => zip.add_directory(
+        path,
+        FileOptions::default().last_modified_time(zip_time(date)),
+      )?,
+      ArchiveWriter::Tar(tar) => {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_mtime(date);
+        header.set_size(0);
+        tar.append_data(&mut header, path, std::io::empty())?;
+      }
+    }
+    Ok(())
+  }
+
+  pub fn add_file(&mut self, path: &str, date: u64, data: &[u8]) -> anyhow::Result<()> {
+    match self {
+      ArchiveWriter::Zip(zip) => {
+        zip.start_file(
+          path,
+          FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .last_modified_time(zip_time(date)),
+        )?;
+        zip.write_all(data)?;
+      }
+      ArchiveWriter::Tar(tar) => {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_mtime(date);
+        header.set_size(data.len() as u64);
+        tar.append_data(&mut header, path, data)?;
+      }
+    }
+    Ok(())
+  }
+
+  pub fn finish(self) -> anyhow::Result<W> {
+    Ok(match self {
+      ArchiveWriter::Zip(mut zip) => zip.finish()?,
+      ArchiveWriter::Tar(tar) => tar.into_inner()?,
+    })
+  }
+}
+
+/// Where an archive is built: a file for plain archives, or memory for ones
+/// that are encrypted as a whole once finished.
+pub enum ArchiveSink {
+  File(BufWriter<File>),
+  Memory(Cursor<Vec<u8>>),
+}
+
+impl Write for ArchiveSink {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      ArchiveSink::File(file) => file.write(buf),
+      ArchiveSink::Memory(data) => data.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      ArchiveSink::File(file) => file.flush(),
+      ArchiveSink::Memory(data) => data.flush(),
+    }
+  }
+}
+
+impl Seek for ArchiveSink {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match self {
+      ArchiveSink::File(file) => file.seek(pos),
+      ArchiveSink::Memory(data) => data.seek(pos),
+    }
+  }
+}
+
+/// `dest` with `.part` appended, where an archive is written until it is
+/// complete.
+fn part_path(dest: &Path) -> PathBuf {
+  let mut name = dest.file_name().unwrap_or_default().to_os_string();
+  name.push(".part");
+  dest.with_file_name(name)
+}
+
+/// Builds an archive with `build` and saves it to `dest`, encrypting it if a
+/// passphrase is given. Plain archives are streamed to disk as they are
+/// built. Either way, `dest` only appears once the archive is complete, and
+/// nothing is left behind if building or writing it fails.
+pub fn write_archive<T>(
+  dest: &Path,
+  format: ArchiveFormat,
+  passphrase: Option<&str>,
+  build: impl FnOnce(&mut ArchiveWriter<ArchiveSink>) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+  let part = part_path(dest);
+  let res = (|| {
+    let sink = match passphrase {
+      Some(_) => ArchiveSink::Memory(Cursor::new(vec![])),
+      None => ArchiveSink::File(BufWriter::new(File::create(&part)?)),
+    };
+    let mut archive = ArchiveWriter::new(format, sink);
+    let value = build(&mut archive)?;
+    match (archive.finish()?, passphrase) {
+      (ArchiveSink::Memory(data), Some(passphrase)) => {
+        File::create(&part)?.write_all(&crypt::encrypt(&data.into_inner(), passphrase)?)?
+      }
+      (mut sink, _) => sink.flush()?,
+    }
+    fs::rename(&part, dest)?;
+    Ok(value)
+  })();
+  if res.is_err() {
+    let _ = fs::remove_file(&part);
+  }
+  res
+}
+
+/// A backup archive read into memory.
+pub struct BackupArchive {
+  pub manifest: Manifest,
+  files: HashMap<String, Vec<u8>>,
+}
+
+impl BackupArchive {
+  /// An archive whose files are already in memory, keyed by manifest path.
+  pub fn new(manifest: Manifest, files: HashMap<String, Vec<u8>>) -> Self {
+    BackupArchive { manifest, files }
+  }
+
+  /// Reads an archive from disk, decrypting it with `passphrase` if it was
+  /// encrypted.
+  pub fn open(path: &Path, passphrase: Option<&str>) -> anyhow::Result<Self> {
+    let format = ArchiveFormat::from_path(path)?;
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+    if crypt::is_encrypted(&data) {
+      let passphrase = passphrase.ok_or_else(|| {
+        anyhow::anyhow!("{} is encrypted, a passphrase is needed", path.display())
+      })?;
+      data = crypt::decrypt(&data, passphrase)?;
+    }
+    Self::read(format, Cursor::new(data))
+  }
+
+  pub fn read<R: Read + Seek>(format: ArchiveFormat, reader: R) -> anyhow::Result<Self> {
+    let mut files = HashMap::new();
+    let mut manifest = None;
+    let mut add = |name: &str, data: Vec<u8>| -> anyhow::Result<()> {
+      if name == MANIFEST {
+        manifest = Some(serde_json::from_slice(&data)?);
+      } else if let Some(path) = name
+        .strip_prefix(FILES_DIR)
+        .and_then(|p| p.strip_prefix('/'))
+      {
+        files.insert(path.to_string(), data);
+      }
+      Ok(())
+    };
+    match format {
+      ArchiveFormat::Zip => {
+        let mut zip = zip::ZipArchive::new(reader)?;
+        for i in 0..zip.len() {
+          let mut file = zip.by_index(i)?;
+          if file.is_dir() {
+            continue;
+          }
+          let mut data = Vec::with_capacity(file.size() as usize);
+          file.read_to_end(&mut data)?;
+          let name = file.name().to_string();
+          add(&name, data)?;
+        }
+      }
+      ArchiveFormat::Tar => {
+        let mut tar = tar::Archive::new(reader);
+        for file in tar.entries()? {
+          let mut file = file?;
+          if file.header().entry_type().is_dir() {
+            continue;
+          }
+          let mut data = vec![];
+          file.read_to_end(&mut data)?;
+          let name = file.path()?.to_string_lossy().replace('\\', "/");
+          add(&name, data)?;
+        }
+      }
+    }
+    let manifest = manifest.ok_or_else(|| anyhow::anyhow!("Archive has no {}", MANIFEST))?;
+    Ok(BackupArchive::new(manifest, files))
+  }
+
+  /// The contents of a file, after checking them against the manifest's
+  /// checksum.
+  pub fn contents(&self, entry: &ManifestEntry) -> anyhow::Result<&[u8]> {
+    let data = self
+      .files
+      .get(&entry.path)
+      .ok_or_else(|| anyhow::anyhow!("{} is missing from the archive", entry.path))?;
+    if let Some(expected) = &entry.sha256 {
+      if &sha256(data) != expected {
+        anyhow::bail!("{} is corrupted in the archive", entry.path);
+      }
+    }
+    Ok(data)
+  }
+}
+
+/// Downloads every entry under `root` into the archive, followed by a
+/// manifest describing them. `progress` is called with the file being read and
+/// the number of bytes left in the whole backup.
+pub fn write_backup<W: Write + Seek>(
+  handle: &libnspire::Handle<GlobalContext>,
+  info: &Info,
+  root: &str,
+  entries: &[TreeEntry],
+  archive: &mut ArchiveWriter<W>,
+  progress: &mut dyn FnMut(&str, usize),
+) -> anyhow::Result<Manifest> {
+  let mut manifest = Manifest::new(info, root);
+  let mut remaining: usize = entries.iter().map(|entry| entry.size as usize).sum();
+  for entry in entries {
+    let name = join(FILES_DIR, &entry.path);
+    if entry.is_dir {
+      archive.add_dir(&format!("{}/", name), entry.date)?;
+      manifest.files.push(ManifestEntry {
+        path: entry.path.clone(),
+        is_dir: true,
+        size: 0,
+        date: entry.date,
+        sha256: None,
+      });
+      continue;
+    }
+    let mut buf = vec![0; entry.size as usize];
+    let before = remaining;
+    let res = handle.read_file(&join(root, &entry.path), &mut buf, &mut |left| {
+      progress(
+        &entry.path,
+        before - (entry.size as usize).saturating_sub(left),
+      )
+    });
+    remaining -= entry.size as usize;
+    match res {
+      Ok(len) => buf.truncate(len),
+      Err(libnspire::Error::NoDevice) => return Err(libnspire::Error::NoDevice.into()),
+      Err(error) => {
+        manifest.skipped.push(SkippedEntry {
+          path: entry.path.clone(),
+          error: error.to_string(),
+        });
+        continue;
+      }
+    }
+    archive.add_file(&name, entry.date, &buf)?;
+    manifest.files.push(ManifestEntry {
+      path: entry.path.clone(),
+      is_dir: false,
+      size: buf.len() as u64,
+      date: entry.date,
+      sha256: Some(sha256(&buf)),
+    });
+  }
+  archive.add_file(
+    MANIFEST,
+    manifest.created,
+    &serde_json::to_vec_pretty(&manifest)?,
+  )?;
+  Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty directory for one test's files.
+  fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("n-link-backup-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn manifest(files: Vec<ManifestEntry>) -> Manifest {
+    Manifest {
+      device_id: "1234".to_string(),
+      device_name: "calc".to_string(),
+      hw_type: "CxCas".to_string(),
+      os_version: "4.5.4".to_string(),
+      created: 1_600_000_000,
+      root: "/documents".to_string(),
+      files,
+      skipped: vec![],
+    }
+  }
+
+  /// Writes a small backup with a folder and a file to `dest`.
+  fn write_sample(dest: &Path, passphrase: Option<&str>) -> anyhow::Result<()> {
+    let data = b"quiz contents";
+    let manifest = manifest(vec![
+      ManifestEntry {
+        path: "class".to_string(),
+        is_dir: true,
+        size: 0,
+        date: 1_600_000_000,
+        sha256: None,
+      },
+      ManifestEntry {
+        path: "class/quiz.tns".to_string(),
+        is_dir: false,
+        size: data.len() as u64,
+        date: 1_600_000_000,
+        sha256: Some(sha256(data)),
+      },
+    ]);
+    let format = ArchiveFormat::from_path(dest)?;
+    write_archive(dest, format, passphrase, |archive| {
+      archive.add_dir("files/class/", 1_600_000_000)?;
+      archive.add_file("files/class/quiz.tns", 1_600_000_000, data)?;
+      archive.add_file(MANIFEST, manifest.created, &serde_json::to_vec(&manifest)?)
+    })
+  }
+
+  fn check_sample(archive: &BackupArchive) {
+    assert_eq!(archive.manifest.device_id, "1234");
+    assert_eq!(archive.manifest.files.len(), 2);
+    let file = &archive.manifest.files[1];
+    assert_eq!(archive.contents(file).unwrap(), b"quiz contents");
+  }
+
+  #[test]
+  fn splits_timestamps_into_dates() {
+    assert_eq!(civil_time(0), (1970, 1, 1, 0, 0, 0));
+    assert_eq!(civil_time(951_825_599), (2000, 2, 29, 11, 59, 59));
+    assert_eq!(civil_time(1_700_000_000), (2023, 11, 14, 22, 13, 20));
+    assert_eq!(civil_time(4_107_542_400), (2100, 3, 1, 0, 0, 0));
+    assert_eq!(format_time(1_609_459_199), "2020-12-31 23:59:59");
+  }
+
+  #[test]
+  fn picks_the_format_from_the_extension() {
+    assert_eq!(
+      ArchiveFormat::from_path(Path::new("a/backup.ZIP")).unwrap(),
+      ArchiveFormat::Zip
+    );
+    assert_eq!(
+      ArchiveFormat::from_path(Path::new("backup.tar")).unwrap(),
+      ArchiveFormat::Tar
+    );
+    assert!(ArchiveFormat::from_path(Path::new("backup.tar.gz")).is_err());
+    assert!(ArchiveFormat::from_path(Path::new("backup")).is_err());
+    assert_eq!(
+      part_path(Path::new("a/backup.zip")),
+      Path::new("a/backup.zip.part")
+    );
+  }
+
+  #[test]
+  fn round_trips_zip_and_tar() {
+    let dir = temp_dir("round-trip");
+    for name in &["backup.zip", "backup.tar"] {
+      let dest = dir.join(name);
+      write_sample(&dest, None).unwrap();
+      assert!(!part_path(&dest).exists());
+      check_sample(&BackupArchive::open(&dest, None).unwrap());
+    }
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn round_trips_encrypted_archives() {
+    let dir = temp_dir("encrypted");
+    let dest = dir.join("backup.zip");
+    write_sample(&dest, Some("secret")).unwrap();
+    assert!(crypt::is_encrypted_file(&dest).unwrap());
+    assert!(BackupArchive::open(&dest, None).is_err());
+    check_sample(&BackupArchive::open(&dest, Some("secret")).unwrap());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn leaves_nothing_behind_on_failure() {
+    let dir = temp_dir("failure");
+    let dest = dir.join("backup.zip");
+    let res: anyhow::Result<()> = write_archive(&dest, ArchiveFormat::Zip, None, |archive| {
+      archive.add_file("files/quiz.tns", 0, b"data")?;
+      anyhow::bail!("calculator disconnected")
+    });
+    assert!(res.is_err());
+    assert!(!dest.exists());
+    assert!(!part_path(&dest).exists());
+    fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn checks_contents_against_the_manifest() {
+    let mut files = HashMap::new();
+    files.insert("quiz.tns".to_string(), b"changed".to_vec());
+    let entry = ManifestEntry {
+      path: "quiz.tns".to_string(),
+      is_dir: false,
+      size: 8,
+      date: 0,
+      sha256: Some(sha256(b"original")),
+    };
+    let missing = ManifestEntry {
+      path: "other.tns".to_string(),
+      ..entry.clone()
+    };
+    let archive = BackupArchive::new(manifest(vec![entry.clone(), missing.clone()]), files);
+    assert!(archive.contents(&entry).is_err());
+    assert!(archive.contents(&missing).is_err());
+  }
+
+  #[test]
+  fn refuses_archives_without_a_manifest() {
+    let mut archive = ArchiveWriter::new(ArchiveFormat::Tar, Cursor::new(vec![]));
+    archive.add_file("files/quiz.tns", 0, b"data").unwrap();
+    let data = archive.finish().unwrap().into_inner();
+    assert!(BackupArchive::read(ArchiveFormat::Tar, Cursor::new(data)).is_err());
+  }
+}
@@ -1,15 +1,43 @@
-use std::ffi::OsStr;
+use std::collections::BTreeSet;
 use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::time::Duration;
 use std::{fs::File, path::Path};
 
 use clap::Clap;
-use indicatif::{ProgressBar, ProgressStyle};
+use crossterm::tty::IsTty;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use libnspire::info::{Info, RunLevel};
 use libnspire::{dir::EntryType, PID, PID_CX2, VID};
 
+use crate::backup::{self, ArchiveFormat, BackupArchive};
+use crate::conflict::{self, ConflictPolicy, Resolution};
+use crate::crypt;
+use crate::diff::{self, Source};
+use crate::extension;
+use crate::history::{self, HistoryFilter, Operation};
+use crate::nspire_path::NspirePath;
+use crate::os_image::{self, OsImage};
+use crate::os_install::{self, InstallPhase};
+use crate::os_library::{self, OsLibrary};
+use crate::restore::{self, RestoreAction};
+use crate::retry::{self, with_retry, RetryPolicy};
+use crate::sanitize;
+use crate::space;
+use crate::store::{SnapshotStats, Store};
+use crate::sync::{self, ActionKind, SyncMode, SyncOptions};
+use crate::verify;
+
 #[derive(Clap, Debug)]
 #[clap(author, about, version)]
 struct Opt {
+  /// How many times to try operations that fail with a timeout or a busy
+  /// calculator
+  #[clap(long, global = true, default_value = "5")]
+  attempts: u32,
+  /// Delay before the first retry in milliseconds, doubled after each one
+  #[clap(long, global = true, default_value = "250")]
+  retry_delay: u64,
   #[clap(subcommand)]
   cmd: Option<SubCommand>,
 }
@@ -19,11 +47,23 @@ enum SubCommand {
   Upload(Upload),
   Download(Download),
   UploadOS(UploadOS),
+  Recover(Recover),
+  Os(Os),
+  /// Show the calculator's model, OS, battery and storage
+  Info,
   Copy(Copy),
   Move(Move),
   Mkdir(Mkdir),
   Rmdir(Rmdir),
   Ls(Ls),
+  Tui(Tui),
+  Sync(Sync),
+  Watch(Watch),
+  Backup(Backup),
+  Restore(Restore),
+  Snapshot(Snapshot),
+  Diff(Diff),
+  History(History),
   /// View license information
   License,
 }
@@ -35,7 +75,18 @@ struct Upload {
   #[clap(required = true, parse(from_os_str))]
   files: Vec<PathBuf>,
   /// Destination path
-  dest: String,
+  dest: NspirePath,
+  /// What to do with files that already exist: skip, overwrite, rename,
+  /// newer-only or ask
+  #[clap(long, default_value = "overwrite")]
+  on_conflict: ConflictPolicy,
+  /// Append the calculator's document extension (usually .tns) to files that
+  /// would otherwise be hidden on the handheld
+  #[clap(long)]
+  add_extension: bool,
+  /// Read each file back after uploading it and compare its contents
+  #[clap(long)]
+  verify: bool,
 }
 
 /// Download files from the calculator
@@ -43,22 +94,93 @@ struct Upload {
 struct Download {
   /// Files to download
   #[clap(required = true)]
-  files: Vec<String>,
+  files: Vec<NspirePath>,
   /// Destination path
   #[clap(required = true, parse(from_os_str))]
   dest: PathBuf,
+  /// What to do with files that already exist: skip, overwrite, rename,
+  /// newer-only or ask
+  #[clap(long, default_value = "overwrite")]
+  on_conflict: ConflictPolicy,
+  /// Remove the calculator's document extension (usually .tns) from the
+  /// downloaded files
+  #[clap(long)]
+  strip_extension: bool,
+  /// Check that each downloaded file has the size the calculator reports
+  #[clap(long)]
+  verify: bool,
 }
 
-/// Upload and install a .tcc/.tco/.tcc2/.tco2/.tct2 OS file
+/// Upload and install a .tno/.tnc/.tco/.tcc/.tco2/.tcc2/.tct2 OS file
 #[derive(Clap, Debug)]
 struct UploadOS {
   /// Path to the OS file
   #[clap(required = true, parse(from_os_str))]
   file: PathBuf,
 
-  /// Disables the file extension check
+  /// Installs the file even if it doesn't look like an OS for this model
   #[clap(long)]
   no_check_os: bool,
+
+  /// Installs even with a low battery, an older OS or an outdated boot2
+  #[clap(long)]
+  force: bool,
+}
+
+/// Install the newest matching OS from a folder on a calculator in recovery
+/// mode
+#[derive(Clap, Debug)]
+struct Recover {
+  /// Folder to look for OS files in, the current one by default
+  #[clap(parse(from_os_str))]
+  dir: Option<PathBuf>,
+
+  /// Installs even with a low battery or an outdated boot2
+  #[clap(long)]
+  force: bool,
+}
+
+/// Keep OS files for all models in one library
+#[derive(Clap, Debug)]
+enum Os {
+  Add(OsAdd),
+  List(OsList),
+  Install(OsInstall),
+}
+
+/// Copy OS files into the library
+#[derive(Clap, Debug)]
+struct OsAdd {
+  /// OS files to add
+  #[clap(required = true, parse(from_os_str))]
+  files: Vec<PathBuf>,
+  /// Library directory, if not the default one
+  #[clap(long, parse(from_os_str))]
+  library: Option<PathBuf>,
+}
+
+/// List the OS files in the library
+#[derive(Clap, Debug)]
+struct OsList {
+  /// Library directory, if not the default one
+  #[clap(long, parse(from_os_str))]
+  library: Option<PathBuf>,
+}
+
+/// Install an OS from the library on the connected calculator
+#[derive(Clap, Debug)]
+struct OsInstall {
+  /// OS version to install, such as 5.3.0.564
+  version: Option<String>,
+  /// Install the newest OS in the library for this calculator
+  #[clap(long, conflicts_with = "version")]
+  latest: bool,
+  /// Installs even with a low battery, an older OS or an outdated boot2
+  #[clap(long)]
+  force: bool,
+  /// Library directory, if not the default one
+  #[clap(long, parse(from_os_str))]
+  library: Option<PathBuf>,
 }
 
 /// Copy a file to a different location
@@ -66,11 +188,11 @@ struct UploadOS {
 struct Copy {
   /// Path to file
   #[clap(required = true)]
-  from_path: String,
+  from_path: NspirePath,
 
   /// Path to new location
   #[clap(required = true)]
-  dist_path: String,
+  dist_path: NspirePath,
 }
 
 /// Move a file or directory to a new location
@@ -78,11 +200,11 @@ struct Copy {
 struct Move {
   /// Path to file
   #[clap(required = true)]
-  from_path: String,
+  from_path: NspirePath,
 
   /// Path to new location
   #[clap(required = true)]
-  dist_path: String,
+  dist_path: NspirePath,
 }
 
 /// Create a directory
@@ -90,7 +212,7 @@ struct Move {
 struct Mkdir {
   /// Path to directory
   #[clap(required = true)]
-  path: String,
+  path: NspirePath,
 }
 
 /// Delete a directory
@@ -98,7 +220,7 @@ struct Mkdir {
 struct Rmdir {
   /// Path to directory
   #[clap(required = true)]
-  path: String,
+  path: NspirePath,
 }
 
 /// List the contents of a directory
@@ -106,21 +228,750 @@ struct Rmdir {
 struct Ls {
   /// Path to directory
   #[clap(required = true)]
-  path: String,
+  path: NspirePath,
+}
+
+/// Browse the calculator in a full-screen two-pane file manager
+#[derive(Clap, Debug)]
+struct Tui {
+  /// Local directory to start in
+  #[clap(parse(from_os_str))]
+  local: Option<PathBuf>,
+  /// Calculator directory to start in
+  #[clap(default_value = "/documents")]
+  remote: NspirePath,
+}
+
+/// Synchronize a local folder with a folder on the calculator
+#[derive(Clap, Debug)]
+struct Sync {
+  /// Local folder
+  #[clap(required = true, parse(from_os_str))]
+  local: PathBuf,
+  /// Calculator folder
+  #[clap(required = true)]
+  remote: NspirePath,
+  /// Direction to copy changes in: push, pull or both
+  #[clap(long, default_value = "both")]
+  mode: SyncMode,
+  /// Delete files on one side when they were deleted on the other
+  #[clap(long)]
+  delete: bool,
+  /// Skip files matching this pattern, such as `*.bak`
+  #[clap(long, number_of_values = 1)]
+  exclude: Vec<String>,
+  /// Print the planned changes without performing them
+  #[clap(long)]
+  dry_run: bool,
+}
+
+/// Upload files to the calculator whenever they change
+#[derive(Clap, Debug)]
+struct Watch {
+  /// Local file or folder to watch
+  #[clap(required = true, parse(from_os_str))]
+  local: PathBuf,
+  /// Calculator folder to upload to
+  #[clap(required = true)]
+  remote: NspirePath,
+  /// Milliseconds to wait for changes to settle before uploading
+  #[clap(long, default_value = "500")]
+  delay: u64,
+  /// Skip files matching this pattern, such as `*.swp`
+  #[clap(long, number_of_values = 1)]
+  exclude: Vec<String>,
 }
 
+/// Back up every file on the calculator to a .zip or .tar archive
+#[derive(Clap, Debug)]
+struct Backup {
+  /// Archive to create
+  #[clap(required = true, parse(from_os_str))]
+  dest: PathBuf,
+  /// Calculator folder to back up
+  #[clap(long, default_value = "/")]
+  root: NspirePath,
+  /// Encrypt the archive with a passphrase
+  #[clap(long)]
+  encrypt: bool,
+}
+
+/// Restore files from a backup archive to the calculator
+#[derive(Clap, Debug)]
+struct Restore {
+  /// Archive created by the backup command
+  #[clap(required = true, parse(from_os_str))]
+  src: PathBuf,
+  /// Calculator folder to restore into, instead of the one that was backed up
+  #[clap(long)]
+  root: Option<NspirePath>,
+  /// What to do with files that already exist: skip, overwrite, rename or
+  /// newer-only
+  #[clap(long, default_value = "skip")]
+  on_conflict: ConflictPolicy,
+}
+
+/// Keep deduplicated snapshots of calculators in a local store
+#[derive(Clap, Debug)]
+enum Snapshot {
+  Create(SnapshotCreate),
+  List(SnapshotList),
+  Prune(SnapshotPrune),
+  Restore(SnapshotRestore),
+}
+
+/// Take a snapshot of the connected calculator, reading only changed files
+#[derive(Clap, Debug)]
+struct SnapshotCreate {
+  /// Store directory
+  #[clap(required = true, parse(from_os_str))]
+  store: PathBuf,
+  /// Calculator folder to back up
+  #[clap(long, default_value = "/")]
+  root: NspirePath,
+}
+
+/// List the snapshots in a store
+#[derive(Clap, Debug)]
+struct SnapshotList {
+  /// Store directory
+  #[clap(required = true, parse(from_os_str))]
+  store: PathBuf,
+  /// Only list snapshots of the calculator with this ID
+  #[clap(long)]
+  device: Option<String>,
+}
+
+/// Delete old snapshots and the files only they used
+#[derive(Clap, Debug)]
+struct SnapshotPrune {
+  /// Store directory
+  #[clap(required = true, parse(from_os_str))]
+  store: PathBuf,
+  /// Number of snapshots to keep for each calculator
+  #[clap(long, required = true)]
+  keep: usize,
+  /// Only prune snapshots of the calculator with this ID
+  #[clap(long)]
+  device: Option<String>,
+}
+
+/// Restore a snapshot to the connected calculator
+#[derive(Clap, Debug)]
+struct SnapshotRestore {
+  /// Store directory
+  #[clap(required = true, parse(from_os_str))]
+  store: PathBuf,
+  /// ID of the calculator the snapshot was taken from, if not the connected one
+  #[clap(long)]
+  device: Option<String>,
+  /// Snapshot to restore, as shown by `snapshot list`, instead of the newest
+  #[clap(long)]
+  at: Option<u64>,
+  /// Calculator folder to restore into, instead of the one that was backed up
+  #[clap(long)]
+  root: Option<NspirePath>,
+  /// What to do with files that already exist: skip, overwrite, rename or
+  /// newer-only
+  #[clap(long, default_value = "skip")]
+  on_conflict: ConflictPolicy,
+}
+
+/// Compare two folders, each on the calculator, on this computer or in a backup
+#[derive(Clap, Debug)]
+struct Diff {
+  /// Calculator folder as `calc:/path`, local folder or backup archive
+  #[clap(required = true)]
+  left: String,
+  /// Calculator folder as `calc:/path`, local folder or backup archive
+  #[clap(required = true)]
+  right: String,
+  /// Compare file contents instead of dates when sizes match
+  #[clap(long)]
+  content: bool,
+}
+
+/// Show past operations on calculators
+#[derive(Clap, Debug)]
+struct History {
+  /// Only show operations on the calculator with this ID or name
+  #[clap(long)]
+  device: Option<String>,
+  /// Only show one kind of operation, such as upload, download or restore
+  #[clap(long)]
+  operation: Option<Operation>,
+  /// Only show operations from the last this many days
+  #[clap(long)]
+  days: Option<u64>,
+  /// Only show operations whose source or destination contains this
+  #[clap(long)]
+  path: Option<String>,
+  /// Export the operations to a CSV file instead of printing them
+  #[clap(long, parse(from_os_str))]
+  csv: Option<PathBuf>,
+}
+
+fn transfer_bar(len: usize, msg: &str) -> ProgressBar {
+  let bar = ProgressBar::new(len as u64);
+  bar.set_style(ProgressStyle::default_bar().template("{spinner:.green} {msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"));
+  bar.set_message(msg);
+  bar.enable_steady_tick(100);
+  bar
+}
+
+fn find_dev() -> Option<rusb::Device<rusb::GlobalContext>> {
+  rusb::devices().unwrap().iter().find(|dev| {
+    let descriptor = match dev.device_descriptor() {
+      Ok(d) => d,
+      Err(_) => return false,
+    };
+    descriptor.vendor_id() == VID && matches!(descriptor.product_id(), PID | PID_CX2)
+  })
+}
+
+/// Opens the calculator, exiting if it is in recovery mode, where only
+/// [`install_os`] works.
 fn get_dev() -> Option<libnspire::Handle<rusb::GlobalContext>> {
-  rusb::devices()
-    .unwrap()
-    .iter()
-    .find(|dev| {
-      let descriptor = match dev.device_descriptor() {
-        Ok(d) => d,
-        Err(_) => return false,
-      };
-      descriptor.vendor_id() == VID && matches!(descriptor.product_id(), PID | PID_CX2)
-    })
-    .map(|dev| libnspire::Handle::new(dev.open().unwrap()).unwrap())
+  let handle = get_any_dev()?;
+  if let Ok(info) = with_retry("Reading device info", || handle.info()) {
+    if info.run_level == RunLevel::Recovery {
+      eprintln!(
+        "{} is in recovery mode and needs an OS. Run `n-link recover` to install one.",
+        info.name
+      );
+      std::process::exit(1);
+    }
+  }
+  Some(handle)
+}
+
+fn get_any_dev() -> Option<libnspire::Handle<rusb::GlobalContext>> {
+  let dev = find_dev()?;
+  let handle = with_retry("Opening the calculator", || {
+    libnspire::Handle::new(dev.open()?)
+  })
+  .unwrap_or_else(|error| {
+    eprintln!("Failed to open the calculator: {}", error);
+    std::process::exit(1);
+  });
+  Some(handle)
+}
+
+/// Like [`get_dev`], but treats a device that can't be opened yet as missing.
+fn try_get_dev() -> Option<libnspire::Handle<rusb::GlobalContext>> {
+  find_dev().and_then(|dev| libnspire::Handle::new(dev.open().ok()?).ok())
+}
+
+/// Starts logging an operation on the connected calculator.
+fn start_history(
+  handle: &libnspire::Handle<rusb::GlobalContext>,
+  operation: Operation,
+  source: Option<&str>,
+  dest: Option<&str>,
+) -> history::Pending {
+  let info =
+    with_retry("Reading device info", || handle.info()).expect("Failed to obtain device info");
+  history::start(&info, operation, source, dest)
+}
+
+/// Asks for the passphrase of an encrypted archive, twice if `confirm` is set.
+fn read_passphrase(confirm: bool) -> anyhow::Result<String> {
+  let passphrase = rpassword::read_password_from_tty(Some("Passphrase: "))?;
+  if passphrase.is_empty() {
+    anyhow::bail!("The passphrase can't be empty");
+  }
+  if confirm && rpassword::read_password_from_tty(Some("Repeat passphrase: "))? != passphrase {
+    anyhow::bail!("The passphrases don't match");
+  }
+  Ok(passphrase)
+}
+
+/// Checks an OS image against the calculator, installs it and waits for the
+/// calculator to come back with it. Exits if any step fails.
+fn install_os(
+  handle: libnspire::Handle<rusb::GlobalContext>,
+  path: &Path,
+  no_check_os: bool,
+  force: bool,
+) {
+  let mut buf = vec![];
+  let mut f = File::open(path).unwrap_or_else(|err| {
+    eprintln!("Failed to open file: {}", err);
+    std::process::exit(1);
+  });
+  f.read_to_end(&mut buf).unwrap();
+  let name = path
+    .file_name()
+    .expect("Failed to get file name")
+    .to_string_lossy()
+    .to_string();
+
+  let calc_info = handle.info().expect("Failed to obtain device info");
+  let is_cx_ii = handle.is_cx_ii().expect("Failed to obtain device info");
+  let location = find_dev()
+    .map(|dev| (dev.bus_number(), dev.address()))
+    .expect("Couldn't find any device");
+
+  let image = OsImage::parse(&name, &buf).unwrap_or_else(|err| {
+    eprintln!("Error: {}", err);
+    std::process::exit(1);
+  });
+  refuse_unless(
+    &image.check(&calc_info, is_cx_ii),
+    no_check_os,
+    "--no-check-os",
+  );
+  refuse_unless(&image.preflight(&calc_info), force, "--force");
+  println!(
+    "Installing {} on {} (currently running {})",
+    image, calc_info.name, calc_info.version
+  );
+
+  let bar = transfer_bar(buf.len(), &format!("Upload OS {}", name));
+
+  let pending = history::start(
+    &calc_info,
+    Operation::UploadOs,
+    Some(&path.display().to_string()),
+    None,
+  );
+  let res = handle.send_os(&buf, &mut |remaining| {
+    bar.set_position((buf.len() - remaining) as u64);
+  });
+
+  match res {
+    Ok(_) => {
+      bar.finish();
+    }
+    Err(error) => {
+      bar.abandon_with_message(&format!("OS Upload failed: {}", error));
+      pending.finish(buf.len() as u64, &Err::<(), _>(error));
+      std::process::exit(1);
+    }
+  }
+  drop(handle);
+
+  let spinner = ProgressBar::new_spinner();
+  spinner.set_message("Installing...");
+  spinner.enable_steady_tick(100);
+  let installed = os_install::wait_for_reboot(&calc_info.id, location, |phase| {
+    if phase == InstallPhase::Rebooting {
+      spinner.set_message("Rebooting...");
+    }
+  })
+  .and_then(|info| {
+    os_install::verify(&image, &info)?;
+    Ok(info)
+  });
+  pending.finish(buf.len() as u64, &installed);
+  match installed {
+    Ok(info) => {
+      spinner.finish_with_message(&format!("{} is now running OS {}", info.name, info.version))
+    }
+    Err(error) => {
+      spinner.abandon_with_message(&format!("OS install failed: {}", error));
+      std::process::exit(1);
+    }
+  }
+}
+
+fn print_info(info: &Info) {
+  println!("Name:       {}", info.name);
+  println!("ID:         {}", info.id);
+  println!("Hardware:   {:?}", info.hw_type);
+  match info.run_level {
+    RunLevel::Recovery => println!("OS:         none, the calculator needs an OS"),
+    _ => println!("OS:         {}", info.version),
+  }
+  println!("Boot1:      {}", info.boot1_version);
+  println!("Boot2:      {}", info.boot2_version);
+  println!(
+    "Battery:    {:?}{}",
+    info.battery,
+    if info.is_charging { ", charging" } else { "" }
+  );
+  println!(
+    "Storage:    {} free of {}",
+    HumanBytes(info.free_storage),
+    HumanBytes(info.total_storage)
+  );
+  println!(
+    "RAM:        {} free of {}",
+    HumanBytes(info.free_ram),
+    HumanBytes(info.total_ram)
+  );
+}
+
+/// Prints `problems`, then exits unless `bypass` is set.
+fn refuse_unless(problems: &[String], bypass: bool, flag: &str) {
+  for problem in problems {
+    if bypass {
+      eprintln!("Warning: {}", problem);
+    } else {
+      eprintln!("Error: {}", problem);
+    }
+  }
+  if !problems.is_empty() && !bypass {
+    eprintln!("Provide {} to bypass this check.", flag);
+    std::process::exit(1);
+  }
+}
+
+/// Asks the user what to do about an existing file. Exits when there is
+/// nobody to ask.
+fn ask_conflict(path: &str) -> ConflictPolicy {
+  if !std::io::stdin().is_tty() {
+    eprintln!(
+      "{} already exists and there is no terminal to ask what to do.",
+      path
+    );
+    eprintln!("Provide --on-conflict skip, overwrite, rename or newer-only.");
+    std::process::exit(1);
+  }
+  loop {
+    print!(
+      "{} already exists. Overwrite, skip or rename? [o/s/r] ",
+      path
+    );
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    match std::io::stdin().read_line(&mut answer) {
+      Ok(0) | Err(_) => return ConflictPolicy::Skip,
+      Ok(_) => {}
+    }
+    match answer.trim().to_lowercase().as_str() {
+      "o" | "overwrite" => return ConflictPolicy::Overwrite,
+      "s" | "skip" => return ConflictPolicy::Skip,
+      "r" | "rename" => return ConflictPolicy::Rename,
+      _ => {}
+    }
+  }
+}
+
+/// Applies a conflict policy to `path`, asking the user if needed. Returns the
+/// destination to write to, or `None` to skip the file.
+fn resolve<P, E>(
+  path: &str,
+  policy: ConflictPolicy,
+  resolve: impl Fn(ConflictPolicy) -> Result<Resolution<P>, E>,
+) -> Result<Option<P>, E> {
+  let mut resolution = resolve(policy)?;
+  if let Resolution::Ask = resolution {
+    resolution = resolve(ask_conflict(path))?;
+  }
+  Ok(match resolution {
+    Resolution::Write(path) => Some(path),
+    Resolution::Skip | Resolution::Ask => None,
+  })
+}
+
+/// Opens a backup archive, asking for its passphrase if it is encrypted.
+fn open_archive(path: &Path) -> anyhow::Result<BackupArchive> {
+  if crypt::is_encrypted_file(path)? {
+    BackupArchive::open(path, Some(&read_passphrase(false)?))
+  } else {
+    BackupArchive::open(path, None)
+  }
+}
+
+/// Interprets a `diff` argument: `calc:/path` for the calculator, an archive
+/// file or a local folder.
+fn parse_source(spec: &str) -> anyhow::Result<Source> {
+  if let Some(path) = spec.strip_prefix("calc:") {
+    return Ok(Source::Calc(NspirePath::parse(path)?));
+  }
+  let path = cwd().join(spec);
+  if path.is_file() {
+    Ok(Source::Archive(Box::new(open_archive(&path)?)))
+  } else if path.is_dir() {
+    Ok(Source::Local(path))
+  } else {
+    anyhow::bail!("{} doesn't exist", path.display())
+  }
+}
+
+/// Restores an archive to the calculator, reporting files that were skipped,
+/// renamed or failed.
+fn restore_archive(
+  handle: &libnspire::Handle<rusb::GlobalContext>,
+  archive: &BackupArchive,
+  root: Option<NspirePath>,
+  on_conflict: ConflictPolicy,
+  source: &str,
+) {
+  let res = (|| -> anyhow::Result<Vec<restore::RestoreResult>> {
+    let info = handle.info()?;
+    if info.id != archive.manifest.device_id {
+      println!(
+        "Note: backup was made from {} ({}), restoring to {} ({})",
+        archive.manifest.device_name, archive.manifest.device_id, info.name, info.id
+      );
+    }
+    let root = match root {
+      Some(root) => root,
+      None => NspirePath::parse(&archive.manifest.root)?,
+    };
+    let plan = restore::plan(handle, archive, &root, on_conflict)?;
+    restore::check_space(&info, &plan)?;
+    let total = plan
+      .items
+      .iter()
+      .filter(|item| item.action.writes())
+      .map(|item| item.size as usize)
+      .sum();
+    let bar = transfer_bar(total, "Restore");
+    let pending = history::start(&info, Operation::Restore, Some(source), Some(&root));
+    let results = restore::restore(handle, archive, &plan, &mut |path, remaining| {
+      bar.set_message(path);
+      bar.set_position((total - remaining) as u64);
+    });
+    match &results {
+      Ok(_) => bar.finish_with_message("Restore: Ok"),
+      Err(_) => bar.abandon_with_message("Restore failed"),
+    }
+    let outcome = match &results {
+      Ok(results) => match results.iter().filter(|res| res.error.is_some()).count() {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} files failed", failed, results.len())),
+      },
+      Err(error) => Err(error.to_string()),
+    };
+    pending.finish(total as u64, &outcome);
+    results
+  })();
+  match res {
+    Ok(results) => {
+      for result in results {
+        match (&result.error, result.item.action) {
+          (Some(error), _) => {
+            eprintln!("Failed to restore {}: {}", result.item.path, error)
+          }
+          (None, RestoreAction::Skip) => {
+            println!("Skipped {}: already exists", result.item.path)
+          }
+          (None, RestoreAction::Rename) => {
+            println!("Restored {} as {}", result.item.path, result.item.dest)
+          }
+          (None, _) => {}
+        }
+      }
+    }
+    Err(error) => {
+      eprintln!("Failed to restore backup: {}", error);
+    }
+  }
+}
+
+fn open_library(dir: Option<PathBuf>) -> anyhow::Result<OsLibrary> {
+  let dir = match dir {
+    Some(dir) => cwd().join(dir),
+    None => os_library::default_dir()?,
+  };
+  OsLibrary::open(&dir)
+}
+
+fn run_os(cmd: Os) {
+  match cmd {
+    Os::Add(OsAdd { files, library }) => {
+      let library = open_library(library).unwrap_or_else(|err| {
+        eprintln!("Failed to open the OS library: {}", err);
+        std::process::exit(1);
+      });
+      for file in files {
+        match library.add(&cwd().join(&file)) {
+          Ok((_, image, true)) => println!("{} is already in the library", image),
+          Ok((_, image, false)) => println!("Added {}", image),
+          Err(error) => eprintln!("Failed to add {}: {}", file.display(), error),
+        }
+      }
+    }
+    Os::List(OsList { library }) => {
+      let res = open_library(library).and_then(|library| library.list());
+      match res {
+        Ok(images) => {
+          for (path, image) in images {
+            println!(
+              "{}  {}  {}",
+              image,
+              HumanBytes(image.size as u64),
+              path.display()
+            );
+          }
+        }
+        Err(error) => eprintln!("Failed to list the OS library: {}", error),
+      }
+    }
+    Os::Install(OsInstall {
+      version,
+      latest,
+      force,
+      library,
+    }) => {
+      if version.is_none() && !latest {
+        eprintln!("Give an OS version or --latest");
+        std::process::exit(1);
+      }
+      if let Some(handle) = get_any_dev() {
+        let info = handle.info().expect("Failed to obtain device info");
+        let is_cx_ii = handle.is_cx_ii().expect("Failed to obtain device info");
+        let res = open_library(library).and_then(|library| match &version {
+          Some(version) => library.find(&info, is_cx_ii, version),
+          None => library.latest(&info, is_cx_ii),
+        });
+        match res {
+          Ok(Some((path, _))) => install_os(handle, &path, false, force),
+          Ok(None) => {
+            eprintln!(
+              "The library has no {} for {}. Add a .{} file with `n-link os add`.",
+              version.map_or_else(|| "OS".to_string(), |version| format!("OS {}", version)),
+              info.name,
+              info.os_extension.trim_start_matches('.')
+            );
+            std::process::exit(1);
+          }
+          Err(error) => {
+            eprintln!("Failed to read the OS library: {}", error);
+            std::process::exit(1);
+          }
+        }
+      } else {
+        eprintln!("Couldn't find any device");
+      }
+    }
+  }
+}
+
+fn run_snapshot(cmd: Snapshot) {
+  match cmd {
+    Snapshot::Create(SnapshotCreate { store, root }) => {
+      if let Some(handle) = get_dev() {
+        let store_dir = cwd().join(store);
+        let res = (|| -> anyhow::Result<(backup::Manifest, SnapshotStats)> {
+          let store = Store::open(&store_dir)?;
+          let info = handle.info()?;
+          let entries = crate::tree::walk_calc(&handle, &root)?;
+          let total = store.to_read(&info.id, &root, &entries)?;
+          let bar = transfer_bar(total, "Snapshot");
+          let pending = history::start(
+            &info,
+            Operation::Snapshot,
+            Some(&root),
+            Some(&store_dir.display().to_string()),
+          );
+          let res = store.backup(&handle, &info, &root, &entries, &mut |path, remaining| {
+            bar.set_message(path);
+            bar.set_position((total - remaining) as u64);
+          });
+          match &res {
+            Ok(_) => bar.finish_with_message("Snapshot: Ok"),
+            Err(_) => bar.abandon_with_message("Snapshot failed"),
+          }
+          pending.finish(total as u64, &res);
+          res
+        })();
+        match res {
+          Ok((manifest, stats)) => {
+            println!(
+              "Snapshot {} of {} ({}): {} files read, {} unchanged, {} new data",
+              manifest.created,
+              manifest.device_name,
+              manifest.device_id,
+              stats.read,
+              stats.unchanged,
+              HumanBytes(stats.stored)
+            );
+            for skipped in manifest.skipped {
+              eprintln!("Skipped {}: {}", skipped.path, skipped.error);
+            }
+          }
+          Err(error) => {
+            eprintln!("Failed to take snapshot: {}", error);
+          }
+        }
+      } else {
+        eprintln!("Couldn't find any device");
+      }
+    }
+    Snapshot::List(SnapshotList { store, device }) => {
+      match Store::open(&cwd().join(store)).and_then(|store| store.snapshots(device.as_deref())) {
+        Ok(snapshots) => {
+          for snapshot in snapshots {
+            let files = snapshot.files.iter().filter(|file| !file.is_dir);
+            println!(
+              "{} {} ({} UTC)  {}  {} files, {}",
+              snapshot.device_id,
+              snapshot.created,
+              backup::format_time(snapshot.created),
+              snapshot.root,
+              files.clone().count(),
+              HumanBytes(files.map(|file| file.size).sum())
+            );
+          }
+        }
+        Err(error) => {
+          eprintln!("Failed to list snapshots: {}", error);
+        }
+      }
+    }
+    Snapshot::Prune(SnapshotPrune {
+      store,
+      keep,
+      device,
+    }) => {
+      match Store::open(&cwd().join(store)).and_then(|store| store.prune(device.as_deref(), keep)) {
+        Ok(result) => {
+          for snapshot in &result.snapshots {
+            println!("Removed {} {}", snapshot.device_id, snapshot.created);
+          }
+          println!(
+            "Removed {} snapshots and {} unused objects, freeing {}",
+            result.snapshots.len(),
+            result.objects,
+            HumanBytes(result.freed)
+          );
+        }
+        Err(error) => {
+          eprintln!("Failed to prune snapshots: {}", error);
+        }
+      }
+    }
+    Snapshot::Restore(SnapshotRestore {
+      store,
+      device,
+      at,
+      root,
+      on_conflict,
+    }) => {
+      if let Some(handle) = get_dev() {
+        let store_dir = cwd().join(store);
+        let archive = (|| -> anyhow::Result<BackupArchive> {
+          let store = Store::open(&store_dir)?;
+          let device = match device {
+            Some(device) => device,
+            None => handle.info()?.id,
+          };
+          let manifest = match at {
+            Some(created) => store.snapshot(&device, created)?,
+            None => store.latest(&device)?,
+          };
+          store.archive(manifest)
+        })();
+        match archive {
+          Ok(archive) => {
+            let source = format!(
+              "{} ({})",
+              store_dir.display(),
+              backup::format_time(archive.manifest.created)
+            );
+            restore_archive(&handle, &archive, root, on_conflict, &source)
+          }
+          Err(error) => eprintln!("Failed to read snapshot: {}", error),
+        }
+      } else {
+        eprintln!("Couldn't find any device");
+      }
+    }
+  }
 }
 
 pub fn cwd() -> PathBuf {
@@ -135,91 +986,210 @@ pub fn cwd() -> PathBuf {
 
 pub fn run() -> bool {
   let opt: Opt = Opt::parse();
+  retry::set_policy(RetryPolicy {
+    max_attempts: opt.attempts.max(1),
+    initial_delay_ms: opt.retry_delay,
+    ..RetryPolicy::default()
+  });
   if let Some(cmd) = opt.cmd {
     match cmd {
-      SubCommand::Upload(Upload { files, mut dest }) => {
+      SubCommand::Upload(Upload {
+        files,
+        dest,
+        on_conflict,
+        add_extension,
+        verify,
+      }) => {
         if let Some(handle) = get_dev() {
+          let info = handle.info().expect("Failed to obtain device info");
+          let ext = extension::doc_extension(&info);
+          let planned: Vec<(String, u64)> = files
+            .iter()
+            .filter_map(|file| {
+              let size = std::fs::metadata(cwd().join(file)).ok()?.len();
+              let name = file.file_name()?.to_string_lossy().to_string();
+              let name = if add_extension {
+                extension::add_extension(&name, ext)
+              } else {
+                name
+              };
+              Some((dest.join(&name).ok()?.to_string(), size))
+            })
+            .collect();
+          if let Err(error) = space::check_upload(
+            &handle,
+            planned.iter().map(|(dest, size)| (dest.as_str(), *size)),
+          ) {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+          }
           for file in files {
             let mut buf = vec![];
-            File::open(cwd().join(&file))
-              .unwrap()
-              .read_to_end(&mut buf)
-              .unwrap();
+            let mut f = File::open(cwd().join(&file)).unwrap();
+            let date = f.metadata().map_or(0, |meta| conflict::local_date(&meta));
+            f.read_to_end(&mut buf).unwrap();
             let name = file
               .file_name()
               .expect("Failed to get file name")
               .to_string_lossy()
               .to_string();
-            let bar = ProgressBar::new(buf.len() as u64);
-            bar.set_style(ProgressStyle::default_bar().template("{spinner:.green} {msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"));
-            bar.set_message(&format!("Upload {}", name));
-            bar.enable_steady_tick(100);
-            if dest.ends_with('/') {
-              dest.remove(dest.len() - 1);
-            }
-            let res = handle.write_file(&format!("{}/{}", dest, name), &buf, &mut |remaining| {
+            let name = if add_extension {
+              extension::add_extension(&name, ext)
+            } else {
+              if !extension::is_visible(&name, ext) {
+                eprintln!("Warning: {}", extension::hidden_warning(&name, ext));
+                eprintln!("Provide --add-extension to rename it.");
+              }
+              name
+            };
+            let target = match dest.join(&name) {
+              Ok(target) => target,
+              Err(error) => {
+                eprintln!("Failed to upload {}: {}", name, error);
+                continue;
+              }
+            };
+            let target = match resolve(&target, on_conflict, |policy| {
+              conflict::resolve_calc(&handle, target.to_string(), date, policy)
+            }) {
+              Ok(Some(target)) => target,
+              Ok(None) => {
+                println!("Skipped {}: already exists", target);
+                continue;
+              }
+              Err(error) => {
+                eprintln!("Failed to check {}: {}", target, error);
+                continue;
+              }
+            };
+            let bar = transfer_bar(buf.len(), &format!("Upload {}", name));
+            let pending = history::start(
+              &info,
+              Operation::Upload,
+              Some(&file.display().to_string()),
+              Some(&target),
+            );
+            let res = handle.write_file(&target, &buf, &mut |remaining| {
               bar.set_position((buf.len() - remaining) as u64)
             });
 
-            match res {
+            let outcome = match res {
+              Ok(_) if verify => {
+                bar.set_message(&format!("Verify {}", name));
+                match verify::verify_upload(&handle, &target, &buf) {
+                  Ok(()) => {
+                    bar.finish_with_message(&format!("Upload {}: Ok, verified", dest));
+                    Ok(())
+                  }
+                  Err(error) => {
+                    bar.abandon_with_message(&format!("Verification failed: {}", error));
+                    Err(error.to_string())
+                  }
+                }
+              }
               Ok(_) => {
                 bar.finish_with_message(&format!("Upload {}: Ok", dest));
+                Ok(())
               }
               Err(error) => {
                 bar.abandon_with_message(&format!("Failed: {}", error));
+                Err(error.to_string())
               }
-            }
+            };
+            pending.finish(buf.len() as u64, &outcome);
           }
         } else {
           eprintln!("Couldn't find any device");
         }
       }
-      SubCommand::Download(Download { dest, files }) => {
+      SubCommand::Download(Download {
+        dest,
+        files,
+        on_conflict,
+        strip_extension,
+        verify,
+      }) => {
         if let Some(handle) = get_dev() {
+          let info = handle.info().expect("Failed to obtain device info");
+          let ext = extension::doc_extension(&info);
           for file in files {
-            let attr = handle.file_attr(&file);
+            let attr = with_retry("Reading file info", || handle.file_attr(&file));
             match attr {
               Ok(attr) => {
-                let path = Path::new(&file);
-                let dest_path = Path::join(&dest, path.file_name().unwrap().to_str().unwrap());
+                let name = match sanitize::calc_file_name(&file) {
+                  Ok(name) if strip_extension => extension::strip_extension(&name, ext).to_string(),
+                  Ok(name) => name,
+                  Err(error) => {
+                    eprintln!("Failed to download {}: {}", file, error);
+                    continue;
+                  }
+                };
+                let dest_path = Path::join(&dest, &name);
+                let dest_path =
+                  match resolve(&dest_path.display().to_string(), on_conflict, |policy| {
+                    conflict::resolve_local(dest_path.clone(), attr.date(), policy)
+                  }) {
+                    Ok(Some(dest_path)) => dest_path,
+                    Ok(None) => {
+                      println!("Skipped {}: already exists", dest_path.display());
+                      continue;
+                    }
+                    Err(error) => {
+                      eprintln!("Failed to check destination file: {}", error);
+                      continue;
+                    }
+                  };
+                let dest_name = dest_path.display().to_string();
                 match File::create(dest_path) {
                   Ok(mut dest_file) => {
                     let mut buf = vec![0u8; attr.size() as usize];
 
-                    let bar = ProgressBar::new(buf.len() as u64);
-                    bar.set_style(ProgressStyle::default_bar().template("{spinner:.green} {msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"));
-                    bar.set_message(&format!(
-                      "Download {}",
-                      path.file_name().unwrap().to_str().unwrap()
-                    ));
-                    bar.enable_steady_tick(100);
+                    let bar = transfer_bar(buf.len(), &format!("Download {}", name));
 
                     let len = buf.len();
 
-                    let res = handle.read_file(&file, &mut buf, &mut |remaining| {
-                      bar.set_position((len - remaining) as u64);
+                    let pending =
+                      history::start(&info, Operation::Download, Some(&file), Some(&dest_name));
+                    let res = with_retry("Download", || {
+                      handle.read_file(&file, &mut buf, &mut |remaining| {
+                        bar.set_position((len - remaining) as u64);
+                      })
                     });
 
-                    match res {
-                      Ok(_) => {
+                    let outcome = match res {
+                      Ok(len) => {
                         bar.set_message("Writing file to disk");
 
-                        match dest_file.write_all(&buf) {
+                        match dest_file.write_all(&buf[..len]) {
+                          Ok(_) if verify => match verify::verify_download(&handle, &file, len) {
+                            Ok(()) => {
+                              bar.finish_with_message("Transfer completed, verified");
+                              Ok(())
+                            }
+                            Err(error) => {
+                              bar.abandon_with_message(&format!("Verification failed: {}", error));
+                              Err(error.to_string())
+                            }
+                          },
                           Ok(_) => {
                             bar.finish_with_message("Transfer completed");
+                            Ok(())
                           }
                           Err(error) => {
                             bar.abandon_with_message(&format!(
                               "Failed to write file to disk: {}",
                               error
                             ));
+                            Err(error.to_string())
                           }
                         }
                       }
                       Err(error) => {
-                        bar.abandon_with_message(&format!("Failed to transfer file: {}", error))
+                        bar.abandon_with_message(&format!("Failed to transfer file: {}", error));
+                        Err(error.to_string())
                       }
-                    }
+                    };
+                    pending.finish(len as u64, &outcome);
                   }
                   Err(error) => {
                     eprintln!("Failed to open destination file: {}", error);
@@ -235,63 +1205,53 @@ pub fn run() -> bool {
           eprintln!("Couldn't find any device");
         }
       }
-      SubCommand::UploadOS(UploadOS { file, no_check_os }) => {
-        if let Some(handle) = get_dev() {
-          let calc_info = handle.info().expect("Failed to obtain device info");
-
-          let file_ext = file
-            .extension()
-            .unwrap_or(OsStr::new(""))
-            .to_string_lossy()
-            .to_string();
-
-          let mut buf = vec![];
-          let mut f = File::open(cwd().join(&file)).unwrap_or_else(|err| {
-            eprintln!("Failed to open file: {}", err);
+      SubCommand::UploadOS(UploadOS {
+        file,
+        no_check_os,
+        force,
+      }) => {
+        if let Some(handle) = get_any_dev() {
+          install_os(handle, &cwd().join(&file), no_check_os, force);
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Recover(Recover { dir, force }) => {
+        if let Some(handle) = get_any_dev() {
+          let info = handle.info().expect("Failed to obtain device info");
+          let is_cx_ii = handle.is_cx_ii().expect("Failed to obtain device info");
+          if info.run_level != RunLevel::Recovery {
+            println!("{} isn't in recovery mode", info.name);
+          }
+          let dir = cwd().join(dir.unwrap_or_default());
+          let images = os_image::find_images(&dir, &info, is_cx_ii).unwrap_or_else(|err| {
+            eprintln!("Failed to read {}: {}", dir.display(), err);
             std::process::exit(1);
           });
-
-          if format!(".{}", file_ext) != calc_info.os_extension {
-            if no_check_os {
-              eprintln!(
-                "Warning: {} expects file of type {}",
-                calc_info.name, calc_info.os_extension
-              );
-            } else {
+          let (path, image) = match images.into_iter().next() {
+            Some(found) => found,
+            None => {
               eprintln!(
-                "Error: {} expects file of type {}",
-                calc_info.name, calc_info.os_extension
+                "No OS image for {} in {}. It needs a .{} file.",
+                info.name,
+                dir.display(),
+                info.os_extension.trim_start_matches('.')
               );
-              eprintln!("Provide --no-check-os to bypass this check.");
               std::process::exit(1);
             }
-          }
-
-          f.read_to_end(&mut buf).unwrap();
-
-          let name = file
-            .file_name()
-            .expect("Failed to get file name")
-            .to_string_lossy()
-            .to_string();
+          };
+          println!("Found {} in {}", image, path.display());
 
-          let bar = ProgressBar::new(buf.len() as u64);
-          bar.set_style(ProgressStyle::default_bar().template("{spinner:.green} {msg} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})"));
-          bar.set_message(&format!("Upload OS {}", name));
-          bar.enable_steady_tick(100);
-
-          let res = handle.send_os(&buf, &mut |remaining| {
-            bar.set_position((buf.len() - remaining) as u64);
-          });
-
-          match res {
-            Ok(_) => {
-              bar.finish();
-            }
-            Err(error) => {
-              bar.abandon_with_message(&format!("OS Upload failed: {}", error));
-            }
-          }
+          install_os(handle, &path, false, force);
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Info => {
+        if let Some(handle) = get_any_dev() {
+          let info = with_retry("Reading device info", || handle.info())
+            .expect("Failed to obtain device info");
+          print_info(&info);
         } else {
           eprintln!("Couldn't find any device");
         }
@@ -301,7 +1261,10 @@ pub fn run() -> bool {
         dist_path,
       }) => {
         if let Some(handle) = get_dev() {
-          match handle.copy_file(&from_path, &dist_path) {
+          let pending = start_history(&handle, Operation::Copy, Some(&from_path), Some(&dist_path));
+          let res = handle.copy_file(&from_path, &dist_path);
+          pending.finish(0, &res);
+          match res {
             Ok(_) => {
               println!("Copy {} => {}: Ok", from_path, dist_path);
             }
@@ -318,7 +1281,10 @@ pub fn run() -> bool {
         dist_path,
       }) => {
         if let Some(handle) = get_dev() {
-          match handle.move_file(&from_path, &dist_path) {
+          let pending = start_history(&handle, Operation::Move, Some(&from_path), Some(&dist_path));
+          let res = handle.move_file(&from_path, &dist_path);
+          pending.finish(0, &res);
+          match res {
             Ok(_) => {
               println!("Move {} => {}: Ok", from_path, dist_path);
             }
@@ -332,7 +1298,10 @@ pub fn run() -> bool {
       }
       SubCommand::Mkdir(Mkdir { path }) => {
         if let Some(handle) = get_dev() {
-          match handle.create_dir(&path) {
+          let pending = start_history(&handle, Operation::CreateDir, None, Some(&path));
+          let res = handle.create_dir(&path);
+          pending.finish(0, &res);
+          match res {
             Ok(_) => {
               println!("Create {}: Ok", path);
             }
@@ -346,7 +1315,10 @@ pub fn run() -> bool {
       }
       SubCommand::Rmdir(Rmdir { path }) => {
         if let Some(handle) = get_dev() {
-          match handle.delete_dir(&path) {
+          let pending = start_history(&handle, Operation::Delete, None, Some(&path));
+          let res = handle.delete_dir(&path);
+          pending.finish(0, &res);
+          match res {
             Ok(_) => {
               println!("Remove {}: Ok", path);
             }
@@ -360,7 +1332,7 @@ pub fn run() -> bool {
       }
       SubCommand::Ls(Ls { path }) => {
         if let Some(handle) = get_dev() {
-          match handle.list_dir(&path) {
+          match with_retry("Listing the folder", || handle.list_dir(&path)) {
             Ok(dir_list) => {
               for item in dir_list.iter() {
                 println!(
@@ -382,6 +1354,293 @@ pub fn run() -> bool {
           eprintln!("Couldn't find any device");
         }
       }
+      SubCommand::Tui(Tui { local, remote }) => {
+        if let Some(handle) = get_dev() {
+          let local = local.map_or_else(cwd, |path| cwd().join(path));
+          if let Err(error) = crate::term::run(handle, local, remote) {
+            eprintln!("Terminal interface failed: {}", error);
+          }
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Sync(Sync {
+        local,
+        remote,
+        mode,
+        delete,
+        exclude,
+        dry_run,
+      }) => {
+        if let Some(handle) = get_dev() {
+          let local = cwd().join(local);
+          let options = SyncOptions {
+            mode,
+            delete,
+            exclude,
+          };
+          match sync::plan(&handle, &local, &remote, &options) {
+            Ok(mut plan) => {
+              if plan.actions.is_empty() {
+                println!("Already in sync");
+              }
+              let pending = (!dry_run).then(|| {
+                start_history(
+                  &handle,
+                  Operation::Sync,
+                  Some(&local.display().to_string()),
+                  Some(&remote),
+                )
+              });
+              let mut failed = 0;
+              let mut skipped = BTreeSet::new();
+              for action in plan.actions.clone() {
+                if action.kind == ActionKind::Conflict {
+                  eprintln!("{}", action);
+                  skipped.insert(action.path);
+                  continue;
+                }
+                if dry_run {
+                  println!("{}", action);
+                  continue;
+                }
+                let len = action.size as usize;
+                let bar = transfer_bar(len, &action.to_string());
+                let res = sync::apply(
+                  &handle,
+                  &local,
+                  &remote,
+                  &mut plan,
+                  &action,
+                  &mut |remaining| bar.set_position((len - remaining.min(len)) as u64),
+                );
+                match res {
+                  Ok(_) => bar.finish_with_message(&format!("{}: Ok", action)),
+                  Err(error) => {
+                    bar.abandon_with_message(&format!("Failed to {}: {}", action, error));
+                    skipped.insert(action.path);
+                    failed += 1;
+                  }
+                }
+              }
+              if let Some(pending) = pending {
+                let size = plan.actions.iter().map(|action| action.size).sum();
+                let outcome = match failed {
+                  0 => Ok(()),
+                  failed => Err(format!(
+                    "{} of {} actions failed",
+                    failed,
+                    plan.actions.len()
+                  )),
+                };
+                pending.finish(size, &outcome);
+              }
+              if !dry_run {
+                if let Err(error) =
+                  sync::save_state(&handle, &local, &remote, plan, &skipped, &options.exclude)
+                {
+                  eprintln!("Failed to save sync state: {}", error);
+                }
+              }
+            }
+            Err(error) => {
+              eprintln!("Failed to compare folders: {}", error);
+            }
+          }
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Watch(Watch {
+        local,
+        remote,
+        delay,
+        exclude,
+      }) => {
+        if let Err(error) = crate::watch::run(
+          &cwd().join(local),
+          &remote,
+          Duration::from_millis(delay),
+          &exclude,
+          &try_get_dev,
+        ) {
+          eprintln!("Failed to watch for changes: {}", error);
+        }
+      }
+      SubCommand::Backup(Backup {
+        dest,
+        root,
+        encrypt,
+      }) => {
+        if let Some(handle) = get_dev() {
+          let dest = cwd().join(dest);
+          let pending = start_history(
+            &handle,
+            Operation::Backup,
+            Some(&root),
+            Some(&dest.display().to_string()),
+          );
+          let res = (|| -> anyhow::Result<backup::Manifest> {
+            let format = ArchiveFormat::from_path(&dest)?;
+            let passphrase = if encrypt {
+              Some(read_passphrase(true)?)
+            } else {
+              None
+            };
+            let info = handle.info()?;
+            let entries = crate::tree::walk_calc(&handle, &root)?;
+            let total = entries.iter().map(|entry| entry.size as usize).sum();
+            let bar = transfer_bar(total, "Backup");
+            let manifest = backup::write_archive(&dest, format, passphrase.as_deref(), |archive| {
+              backup::write_backup(
+                &handle,
+                &info,
+                &root,
+                &entries,
+                archive,
+                &mut |path, remaining| {
+                  bar.set_message(path);
+                  bar.set_position((total - remaining) as u64);
+                },
+              )
+            });
+            let manifest = match manifest {
+              Ok(manifest) => manifest,
+              Err(error) => {
+                bar.abandon_with_message("Backup failed");
+                return Err(error);
+              }
+            };
+            bar.finish_with_message(&format!("Backup {}: Ok", dest.display()));
+            Ok(manifest)
+          })();
+          let size = res.as_ref().map_or(0, |manifest| {
+            manifest.files.iter().map(|file| file.size).sum()
+          });
+          pending.finish(size, &res);
+          match res {
+            Ok(manifest) => {
+              println!(
+                "Backed up {} files from {} ({}, OS {})",
+                manifest.files.iter().filter(|file| !file.is_dir).count(),
+                manifest.device_name,
+                manifest.device_id,
+                manifest.os_version
+              );
+              for skipped in manifest.skipped {
+                eprintln!("Skipped {}: {}", skipped.path, skipped.error);
+              }
+            }
+            Err(error) => {
+              eprintln!("Failed to back up calculator: {}", error);
+            }
+          }
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Restore(Restore {
+        src,
+        root,
+        on_conflict,
+      }) => {
+        if let Some(handle) = get_dev() {
+          let src = cwd().join(src);
+          match open_archive(&src) {
+            Ok(archive) => restore_archive(
+              &handle,
+              &archive,
+              root,
+              on_conflict,
+              &src.display().to_string(),
+            ),
+            Err(error) => eprintln!("Failed to read backup: {}", error),
+          }
+        } else {
+          eprintln!("Couldn't find any device");
+        }
+      }
+      SubCommand::Snapshot(cmd) => run_snapshot(cmd),
+      SubCommand::Os(cmd) => run_os(cmd),
+      SubCommand::Diff(Diff {
+        left,
+        right,
+        content,
+      }) => {
+        let res = (|| -> anyhow::Result<Vec<diff::Difference>> {
+          let left = parse_source(&left)?;
+          let right = parse_source(&right)?;
+          let handle = if left.is_calc() || right.is_calc() {
+            get_dev()
+          } else {
+            None
+          };
+          diff::diff(&left, &right, handle.as_ref(), content)
+        })();
+        match res {
+          Ok(differences) => {
+            if differences.is_empty() {
+              println!("No differences");
+            }
+            for difference in differences {
+              println!("{}", difference);
+              if let Some(text) = difference.text {
+                print!("{}", text);
+              }
+            }
+          }
+          Err(error) => {
+            eprintln!("Failed to compare: {}", error);
+          }
+        }
+      }
+      SubCommand::History(History {
+        device,
+        operation,
+        days,
+        path,
+        csv,
+      }) => {
+        let filter = HistoryFilter {
+          device,
+          operation,
+          since: days.map(|days| backup::now().saturating_sub(days * 24 * 60 * 60)),
+          path,
+        };
+        let res = history::default_path().and_then(|log| history::read(&log, &filter));
+        match res {
+          Ok(entries) => match csv {
+            Some(csv) => match std::fs::write(cwd().join(&csv), history::to_csv(&entries)) {
+              Ok(()) => println!("Exported {} operations to {}", entries.len(), csv.display()),
+              Err(error) => eprintln!("Failed to write {}: {}", csv.display(), error),
+            },
+            None => {
+              for entry in entries {
+                let paths = match (&entry.source, &entry.dest) {
+                  (Some(source), Some(dest)) => format!("{} => {}", source, dest),
+                  (Some(path), None) | (None, Some(path)) => path.clone(),
+                  (None, None) => String::new(),
+                };
+                println!(
+                  "{} UTC  {} ({})  {}  {}  {}, {:.1} s  {}",
+                  backup::format_time(entry.time),
+                  entry.device_name,
+                  entry.device_id,
+                  entry.operation.name(),
+                  paths,
+                  HumanBytes(entry.size),
+                  entry.duration_ms as f64 / 1000.0,
+                  match &entry.error {
+                    Some(error) => format!("failed: {}", error),
+                    None => "ok".to_string(),
+                  }
+                );
+              }
+            }
+          },
+          Err(error) => eprintln!("Failed to read the history: {}", error),
+        }
+      }
       SubCommand::License => {
         println!("{}", include_str!("../../LICENSE"));
         println!(include_str!("NOTICE.txt"), env!("CARGO_PKG_REPOSITORY"));
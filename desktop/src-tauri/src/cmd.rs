@@ -6,6 +6,9 @@ use rusb::GlobalContext;
 use serde::{Deserialize, Serialize};
 use tauri::{Runtime, Window};
 
+use crate::os_install::InstallPhase;
+use crate::sync::Action;
+use crate::tree::{ChangeKind, TreeEntry};
 use crate::{Device, DeviceState, SerializedError};
 
 #[derive(Serialize, Deserialize)]
@@ -114,13 +117,65 @@ pub struct AddDevice {
   pub needs_drivers: bool,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OperationKind {
+  Upload,
+  Download,
+  DownloadZip,
+  UploadOs,
+  Sync,
+  Backup,
+  Restore,
+  Snapshot,
+  Delete,
+  Copy,
+  Move,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProgressUpdate {
   #[serde(flatten)]
   pub dev: DevId,
+  /// Tells apart operations running at the same time. Unique for the whole
+  /// session.
+  pub operation_id: u64,
+  pub kind: OperationKind,
+  /// The file being transferred.
+  pub path: Option<String>,
   pub remaining: usize,
   pub total: usize,
+  pub bytes_per_sec: u64,
+  /// Seconds left at the current speed, once there is one.
+  pub eta_secs: Option<u64>,
+  /// Position of the current file in a batch, starting at 1.
+  pub batch_index: Option<usize>,
+  pub batch_len: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryUpdate {
+  #[serde(flatten)]
+  pub dev: DevId,
+  /// What is being retried, such as `list_dir`.
+  pub operation: String,
+  /// The attempt about to be made, starting at 2.
+  pub attempt: u32,
+  pub max_attempts: u32,
+  pub error: String,
+  pub delay_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallUpdate {
+  #[serde(flatten)]
+  pub dev: DevId,
+  pub phase: InstallPhase,
+  /// The OS version once it is verified, or why the install failed.
+  pub message: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -131,3 +186,55 @@ pub struct FileInfo {
   pub date: u64,
   pub size: u64,
 }
+
+#[derive(Copy, Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferStatus {
+  Written,
+  Skipped,
+  /// The destination exists and the caller asked to decide.
+  Conflict,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TransferResult {
+  /// Where the file was (or would have been) written.
+  pub path: String,
+  pub status: TransferStatus,
+  /// Something the user should know about the transfer, such as the file
+  /// being hidden on the handheld.
+  pub warning: Option<String>,
+  /// Whether the file checked out after the transfer, if it was verified.
+  pub verified: Option<bool>,
+  /// Why verification failed.
+  pub verify_error: Option<String>,
+}
+
+/// The outcome of one item of a batch command.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResult<T> {
+  /// The calculator path the item refers to.
+  pub path: String,
+  pub result: Option<T>,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+  pub action: Action,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteChange {
+  #[serde(flatten)]
+  pub dev: DevId,
+  pub kind: ChangeKind,
+  pub entry: TreeEntry,
+  /// Set if mirroring the change to the local folder failed.
+  pub error: Option<String>,
+}
@@ -0,0 +1,212 @@
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use rusb::GlobalContext;
+use serde::{Deserialize, Serialize};
+
+/// What to do when the destination of a transfer already exists.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPolicy {
+  Skip,
+  Overwrite,
+  /// Write under a new name with a numbered suffix.
+  Rename,
+  /// Overwrite only if the source was modified more recently.
+  NewerOnly,
+  /// Let the user decide for each file.
+  Ask,
+}
+
+impl FromStr for ConflictPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "skip" => Ok(ConflictPolicy::Skip),
+      "overwrite" => Ok(ConflictPolicy::Overwrite),
+      "rename" => Ok(ConflictPolicy::Rename),
+      "newer-only" => Ok(ConflictPolicy::NewerOnly),
+      "ask" => Ok(ConflictPolicy::Ask),
+      other => Err(format!("Unknown conflict policy {}", other)),
+    }
+  }
+}
+
+/// The outcome of applying a policy to one destination.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Resolution<P> {
+  /// Write to this path, which may differ from the one asked for.
+  Write(P),
+  Skip,
+  /// The destination exists and the user has to choose.
+  Ask,
+}
+
+/// Modification time of a local file, in seconds since the Unix epoch.
+pub fn local_date(meta: &Metadata) -> u64 {
+  meta
+    .modified()
+    .ok()
+    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+    .map_or(0, |time| time.as_secs())
+}
+
+/// The `n`th alternative name for a file: `name_1.tns`, `name_2.tns`...
+pub fn renamed(path: &str, n: usize) -> String {
+  let (dir, name) = match path.rfind('/') {
+    Some(idx) => (&path[..=idx], &path[idx + 1..]),
+    None => ("", path),
+  };
+  match name.find('.') {
+    Some(idx) if idx > 0 => format!("{}{}_{}{}", dir, &name[..idx], n, &name[idx..]),
+    _ => format!("{}{}_{}", dir, name, n),
+  }
+}
+
+/// Applies `policy` to a local destination. `src_date` is the modification
+/// time of the source, in seconds since the Unix epoch.
+pub fn resolve_local(
+  dest: PathBuf,
+  src_date: u64,
+  policy: ConflictPolicy,
+) -> io::Result<Resolution<PathBuf>> {
+  let meta = match fs::metadata(&dest) {
+    Ok(meta) => meta,
+    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Resolution::Write(dest)),
+    Err(e) => return Err(e),
+  };
+  Ok(match policy {
+    ConflictPolicy::Skip => Resolution::Skip,
+    ConflictPolicy::Overwrite => Resolution::Write(dest),
+    ConflictPolicy::Ask => Resolution::Ask,
+    ConflictPolicy::NewerOnly => {
+      if src_date > local_date(&meta) {
+        Resolution::Write(dest)
+      } else {
+        Resolution::Skip
+      }
+    }
+    ConflictPolicy::Rename => {
+      let name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+      Resolution::Write(
+        (1..)
+          .map(|n| dest.with_file_name(renamed(&name, n)))
+          .find(|path| !path.exists())
+          .unwrap(),
+      )
+    }
+  })
+}
+
+/// Applies `policy` to a destination on the calculator, checking it with
+/// `file_attr`.
+pub fn resolve_calc(
+  handle: &libnspire::Handle<GlobalContext>,
+  dest: String,
+  src_date: u64,
+  policy: ConflictPolicy,
+) -> libnspire::Result<Resolution<String>> {
+  let attr = match handle.file_attr(&dest) {
+    Ok(attr) => attr,
+    Err(libnspire::Error::DoesNotExist) => return Ok(Resolution::Write(dest)),
+    Err(e) => return Err(e),
+  };
+  Ok(match policy {
+    ConflictPolicy::Skip => Resolution::Skip,
+    ConflictPolicy::Overwrite => Resolution::Write(dest),
+    ConflictPolicy::Ask => Resolution::Ask,
+    ConflictPolicy::NewerOnly => {
+      if src_date > attr.date() {
+        Resolution::Write(dest)
+      } else {
+        Resolution::Skip
+      }
+    }
+    ConflictPolicy::Rename => {
+      let mut n = 1;
+      loop {
+        let path = renamed(&dest, n);
+        match handle.file_attr(&path) {
+          Ok(_) => n += 1,
+          Err(libnspire::Error::DoesNotExist) => return Ok(Resolution::Write(path)),
+          Err(e) => return Err(e),
+        }
+      }
+    }
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_policies() {
+    assert_eq!("newer-only".parse(), Ok(ConflictPolicy::NewerOnly));
+    assert_eq!("ask".parse(), Ok(ConflictPolicy::Ask));
+    assert!("always".parse::<ConflictPolicy>().is_err());
+  }
+
+  #[test]
+  fn numbers_renamed_files() {
+    assert_eq!(renamed("quiz.tns", 1), "quiz_1.tns");
+    assert_eq!(renamed("quiz.py.tns", 2), "quiz_2.py.tns");
+    assert_eq!(renamed("notes", 1), "notes_1");
+    assert_eq!(renamed(".hidden", 1), ".hidden_1");
+    assert_eq!(renamed("/documents/quiz.tns", 3), "/documents/quiz_3.tns");
+    assert_eq!(renamed("class.old/quiz", 1), "class.old/quiz_1");
+  }
+
+  #[test]
+  fn resolves_local_conflicts() {
+    let dir = std::env::temp_dir().join(format!("n-link-conflict-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let dest = dir.join("quiz.tns");
+    let missing = dir.join("other.tns");
+    for policy in &[
+      ConflictPolicy::Skip,
+      ConflictPolicy::Overwrite,
+      ConflictPolicy::Rename,
+      ConflictPolicy::NewerOnly,
+      ConflictPolicy::Ask,
+    ] {
+      assert_eq!(
+        resolve_local(missing.clone(), 0, *policy).unwrap(),
+        Resolution::Write(missing.clone())
+      );
+    }
+
+    fs::write(&dest, b"old").unwrap();
+    fs::write(dir.join("quiz_1.tns"), b"old").unwrap();
+    let date = local_date(&fs::metadata(&dest).unwrap());
+    let resolve = |src_date, policy| resolve_local(dest.clone(), src_date, policy).unwrap();
+    assert_eq!(resolve(date, ConflictPolicy::Skip), Resolution::Skip);
+    assert_eq!(resolve(date, ConflictPolicy::Ask), Resolution::Ask);
+    assert_eq!(
+      resolve(date, ConflictPolicy::Overwrite),
+      Resolution::Write(dest.clone())
+    );
+    assert_eq!(
+      resolve(date, ConflictPolicy::Rename),
+      Resolution::Write(dir.join("quiz_2.tns"))
+    );
+    assert_eq!(
+      resolve(date + 1, ConflictPolicy::NewerOnly),
+      Resolution::Write(dest.clone())
+    );
+    assert_eq!(resolve(date, ConflictPolicy::NewerOnly), Resolution::Skip);
+    assert_eq!(
+      resolve(date - 1, ConflictPolicy::NewerOnly),
+      Resolution::Skip
+    );
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
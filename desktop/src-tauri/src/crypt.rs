@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use rand::RngCore;
+use sha2::Sha256;
+
+/// Marks the start of an encrypted archive.
+const MAGIC: &[u8; 8] = b"NLINKENC";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// PBKDF2 rounds used for new archives. The count is stored in the header so
+/// it can be raised later without breaking old archives.
+const ROUNDS: u32 = 200_000;
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN + NONCE_LEN;
+
+fn derive_key(passphrase: &str, salt: &[u8], rounds: u32) -> Key {
+  let mut key = Key::default();
+  pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+  key
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+  data.starts_with(MAGIC)
+}
+
+/// Whether the file at `path` starts with the encrypted archive header.
+pub fn is_encrypted_file(path: &Path) -> anyhow::Result<bool> {
+  let mut magic = [0; MAGIC.len()];
+  let mut file = File::open(path)?;
+  let mut read = 0;
+  while read < magic.len() {
+    match file.read(&mut magic[read..])? {
+      0 => return Ok(false),
+      n => read += n,
+    }
+  }
+  Ok(is_encrypted(&magic))
+}
+
+/// Encrypts `data` with ChaCha20-Poly1305, using a key derived from
+/// `passphrase` with a fresh random salt. The header is authenticated too.
+pub fn encrypt(data: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+  let mut salt = [0; SALT_LEN];
+  let mut nonce = [0; NONCE_LEN];
+  rand::thread_rng().fill_bytes(&mut salt);
+  rand::thread_rng().fill_bytes(&mut nonce);
+  let mut out = Vec::with_capacity(HEADER_LEN + data.len() + 16);
+  out.extend_from_slice(MAGIC);
+  out.extend_from_slice(&ROUNDS.to_le_bytes());
+  out.extend_from_slice(&salt);
+  out.extend_from_slice(&nonce);
+  let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, ROUNDS));
+  let encrypted = cipher
+    .encrypt(
+      &Nonce::from(nonce),
+      Payload {
+        msg: data,
+        aad: &out,
+      },
+    )
+    .map_err(|_| anyhow::anyhow!("Failed to encrypt archive"))?;
+  out.extend_from_slice(&encrypted);
+  Ok(out)
+}
+
+/// Reverses [`encrypt`], failing if the passphrase is wrong or the data was
+/// modified.
+pub fn decrypt(data: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+  if !is_encrypted(data) || data.len() < HEADER_LEN {
+    anyhow::bail!("Not an encrypted archive");
+  }
+  let (header, encrypted) = data.split_at(HEADER_LEN);
+  let mut rounds = [0; 4];
+  rounds.copy_from_slice(&header[MAGIC.len()..MAGIC.len() + 4]);
+  let rounds = u32::from_le_bytes(rounds);
+  if rounds == 0 || rounds > 100 * ROUNDS {
+    anyhow::bail!("Unsupported encrypted archive");
+  }
+  let salt = &header[MAGIC.len() + 4..MAGIC.len() + 4 + SALT_LEN];
+  let mut nonce = [0; NONCE_LEN];
+  nonce.copy_from_slice(&header[MAGIC.len() + 4 + SALT_LEN..]);
+  let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, salt, rounds));
+  cipher
+    .decrypt(
+      &Nonce::from(nonce),
+      Payload {
+        msg: encrypted,
+        aad: header,
+      },
+    )
+    .map_err(|_| anyhow::anyhow!("Wrong passphrase, or the archive is damaged"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set_rounds(data: &mut [u8], rounds: u32) {
+    data[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&rounds.to_le_bytes());
+  }
+
+  #[test]
+  fn round_trips() {
+    let encrypted = encrypt(b"backup contents", "correct horse").unwrap();
+    assert!(is_encrypted(&encrypted));
+    assert_eq!(
+      decrypt(&encrypted, "correct horse").unwrap(),
+      b"backup contents"
+    );
+  }
+
+  #[test]
+  fn rejects_wrong_passphrase() {
+    let encrypted = encrypt(b"backup contents", "correct horse").unwrap();
+    assert!(decrypt(&encrypted, "battery staple").is_err());
+  }
+
+  #[test]
+  fn rejects_tampered_header_and_data() {
+    let encrypted = encrypt(b"backup contents", "correct horse").unwrap();
+    let mut salt = encrypted.clone();
+    salt[MAGIC.len() + 4] ^= 1;
+    assert!(decrypt(&salt, "correct horse").is_err());
+    let mut rounds = encrypted.clone();
+    set_rounds(&mut rounds, ROUNDS + 1);
+    assert!(decrypt(&rounds, "correct horse").is_err());
+    let mut body = encrypted;
+    *body.last_mut().unwrap() ^= 1;
+    assert!(decrypt(&body, "correct horse").is_err());
+  }
+
+  #[test]
+  fn rejects_unsupported_round_counts() {
+    let mut encrypted = encrypt(b"backup contents", "correct horse").unwrap();
+    for rounds in &[0, 100 * ROUNDS + 1, u32::MAX] {
+      set_rounds(&mut encrypted, *rounds);
+      let error = decrypt(&encrypted, "correct horse").unwrap_err();
+      assert_eq!(error.to_string(), "Unsupported encrypted archive");
+    }
+  }
+
+  #[test]
+  fn rejects_plain_and_short_data() {
+    assert!(decrypt(b"PK\x03\x04", "correct horse").is_err());
+    assert!(decrypt(MAGIC, "correct horse").is_err());
+  }
+}
@@ -0,0 +1,240 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+use indicatif::HumanBytes;
+use rusb::GlobalContext;
+use similar::TextDiff;
+
+use crate::backup::{format_time, sha256, BackupArchive};
+use crate::nspire_path::NspirePath;
+use crate::tree::{join, walk_calc, walk_local, TreeEntry};
+
+type Handle = libnspire::Handle<GlobalContext>;
+
+/// Files larger than this are never shown as text diffs.
+const TEXT_DIFF_LIMIT: u64 = 64 * 1024;
+
+/// One side of a comparison.
+pub enum Source {
+  /// A folder on the calculator.
+  Calc(NspirePath),
+  Local(PathBuf),
+  Archive(Box<BackupArchive>),
+}
+
+impl Source {
+  pub fn is_calc(&self) -> bool {
+    matches!(self, Source::Calc(_))
+  }
+
+  fn name(&self) -> String {
+    match self {
+      Source::Calc(path) => format!("calc:{}", path),
+      Source::Local(path) => path.display().to_string(),
+      Source::Archive(archive) => format!(
+        "backup of {} at {}",
+        archive.manifest.device_name,
+        format_time(archive.manifest.created)
+      ),
+    }
+  }
+
+  fn list(&self, handle: Option<&Handle>) -> anyhow::Result<BTreeMap<String, TreeEntry>> {
+    let entries = match self {
+      Source::Calc(path) => walk_calc(calc(handle)?, path)?,
+      Source::Local(path) => walk_local(path)?,
+      Source::Archive(archive) => archive
+        .manifest
+        .files
+        .iter()
+        .map(|entry| TreeEntry {
+          path: entry.path.clone(),
+          is_dir: entry.is_dir,
+          size: entry.size,
+          date: entry.date,
+        })
+        .collect(),
+    };
+    Ok(
+      entries
+        .into_iter()
+        .filter(|entry| !entry.is_dir)
+        .map(|entry| (entry.path.clone(), entry))
+        .collect(),
+    )
+  }
+
+  fn read(&self, handle: Option<&Handle>, entry: &TreeEntry) -> anyhow::Result<Vec<u8>> {
+    Ok(match self {
+      Source::Calc(path) => {
+        let mut buf = vec![0; entry.size as usize];
+        let len = calc(handle)?.read_file(&join(path, &entry.path), &mut buf, &mut |_| {})?;
+        buf.truncate(len);
+        buf
+      }
+      Source::Local(path) => {
+        let mut buf = vec![];
+        File::open(path.join(&entry.path))?.read_to_end(&mut buf)?;
+        buf
+      }
+      Source::Archive(archive) => {
+        let file = archive
+          .manifest
+          .files
+          .iter()
+          .find(|file| file.path == entry.path)
+          .ok_or_else(|| anyhow::anyhow!("{} is missing from the archive", entry.path))?;
+        archive.contents(file)?.to_vec()
+      }
+    })
+  }
+
+  /// The SHA-256 of a file, taken from the manifest for archives.
+  fn hash(&self, handle: Option<&Handle>, entry: &TreeEntry) -> anyhow::Result<String> {
+    if let Source::Archive(archive) = self {
+      let known = archive
+        .manifest
+        .files
+        .iter()
+        .find(|file| file.path == entry.path)
+        .and_then(|file| file.sha256.clone());
+      if let Some(hash) = known {
+        return Ok(hash);
+      }
+    }
+    Ok(sha256(&self.read(handle, entry)?))
+  }
+}
+
+fn calc(handle: Option<&Handle>) -> anyhow::Result<&Handle> {
+  handle.ok_or_else(|| anyhow::anyhow!("Couldn't find any device"))
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DiffKind {
+  /// Only on the right side.
+  Added,
+  /// Only on the left side.
+  Removed,
+  Changed,
+}
+
+pub struct Difference {
+  pub kind: DiffKind,
+  pub path: String,
+  pub left: Option<TreeEntry>,
+  pub right: Option<TreeEntry>,
+  /// Unified diff of the two versions, for small text files.
+  pub text: Option<String>,
+}
+
+impl std::fmt::Display for Difference {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match (self.kind, &self.left, &self.right) {
+      (DiffKind::Added, _, Some(right)) => {
+        write!(f, "+ {} ({})", self.path, HumanBytes(right.size))
+      }
+      (DiffKind::Removed, Some(left), _) => {
+        write!(f, "- {} ({})", self.path, HumanBytes(left.size))
+      }
+      (DiffKind::Changed, Some(left), Some(right)) => write!(
+        f,
+        "M {} ({}, {} -> {}, {})",
+        self.path,
+        HumanBytes(left.size),
+        format_time(left.date),
+        HumanBytes(right.size),
+        format_time(right.date)
+      ),
+      _ => write!(f, "? {}", self.path),
+    }
+  }
+}
+
+/// Extensions of files that are never text, so they aren't read just to find
+/// that out. Calculator documents and OS images are binary.
+const BINARY_EXTENSIONS: &[&str] = &[
+  "tns", "tno", "tnc", "tco", "tcc", "tco2", "tcc2", "tct2", "zip", "tar", "png", "jpg", "jpeg",
+  "gif", "bmp", "pdf",
+];
+
+/// Whether a file may be text, judging by its name alone.
+fn may_be_text(path: &str) -> bool {
+  let name = path.rsplit('/').next().unwrap_or(path);
+  match name.rfind('.') {
+    Some(dot) => !BINARY_EXTENSIONS.contains(&name[dot + 1..].to_lowercase().as_str()),
+    None => true,
+  }
+}
+
+/// Whether a file looks like text worth showing line by line.
+fn is_text(data: &[u8]) -> bool {
+  !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Compares the files of two sources. Files are changed when their size
+/// differs, and otherwise when their date differs or, if `content` is set,
+/// when their SHA-256 differs.
+pub fn diff(
+  left: &Source,
+  right: &Source,
+  handle: Option<&Handle>,
+  content: bool,
+) -> anyhow::Result<Vec<Difference>> {
+  let left_files = left.list(handle)?;
+  let right_files = right.list(handle)?;
+  let paths: BTreeSet<&String> = left_files.keys().chain(right_files.keys()).collect();
+  let mut differences = vec![];
+  for path in paths {
+    let l = left_files.get(path);
+    let r = right_files.get(path);
+    let kind = match (l, r) {
+      (Some(l), Some(r)) => {
+        let changed = if l.size != r.size {
+          true
+        } else if content {
+          left.hash(handle, l)? != right.hash(handle, r)?
+        } else {
+          l.date != r.date
+        };
+        if !changed {
+          continue;
+        }
+        DiffKind::Changed
+      }
+      (Some(_), None) => DiffKind::Removed,
+      (None, Some(_)) => DiffKind::Added,
+      (None, None) => continue,
+    };
+    let mut text = None;
+    if let (Some(l), Some(r)) = (l, r) {
+      if l.size <= TEXT_DIFF_LIMIT && r.size <= TEXT_DIFF_LIMIT && may_be_text(path) {
+        let old = left.read(handle, l)?;
+        let new = right.read(handle, r)?;
+        if is_text(&old) && is_text(&new) && old != new {
+          let old = String::from_utf8_lossy(&old);
+          let new = String::from_utf8_lossy(&new);
+          text = Some(
+            TextDiff::from_lines(&old, &new)
+              .unified_diff()
+              .header(
+                &format!("{}/{}", left.name(), path),
+                &format!("{}/{}", right.name(), path),
+              )
+              .to_string(),
+          );
+        }
+      }
+    }
+    differences.push(Difference {
+      kind,
+      path: path.clone(),
+      left: l.cloned(),
+      right: r.cloned(),
+      text,
+    });
+  }
+  Ok(differences)
+}
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use rusb::GlobalContext;
+use serde::Serialize;
+
+use crate::backup::{ArchiveFormat, ArchiveWriter, SkippedEntry};
+use crate::conflict;
+use crate::nspire_path::NspirePath;
+use crate::sanitize;
+use crate::tree::{join, walk_calc};
+
+/// A calculator file or directory to put in an export.
+#[derive(Clone, Debug)]
+pub struct ExportEntry {
+  /// Full path on the calculator.
+  pub src: String,
+  /// Path inside the archive.
+  pub name: String,
+  pub is_dir: bool,
+  pub size: u64,
+  pub date: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportSummary {
+  pub files: usize,
+  pub size: u64,
+  /// Files that couldn't be read and were left out.
+  pub skipped: Vec<SkippedEntry>,
+}
+
+/// Lists what exporting `paths` puts in the archive. Each path is stored
+/// under its own name, with directories including everything in them. Names
+/// are made safe to extract on any OS, and ones that would clash get a
+/// number added.
+pub fn plan(
+  handle: &libnspire::Handle<GlobalContext>,
+  paths: &[NspirePath],
+) -> anyhow::Result<Vec<ExportEntry>> {
+  let mut entries = vec![];
+  let mut used = HashSet::new();
+  for path in paths {
+    let attr = handle.file_attr(path)?;
+    let base = match path.file_name() {
+      None => String::new(),
+      Some(name) => unique_name(sanitize::file_name(name)?, &mut used),
+    };
+    if attr.entry_type() != libnspire::dir::EntryType::Directory {
+      entries.push(ExportEntry {
+        src: path.to_string(),
+        name: base,
+        is_dir: false,
+        size: attr.size(),
+        date: attr.date(),
+      });
+      continue;
+    }
+    if !base.is_empty() {
+      entries.push(ExportEntry {
+        src: path.to_string(),
+        name: base.clone(),
+        is_dir: true,
+        size: 0,
+        date: attr.date(),
+      });
+    }
+    // Archive names of the directories listed so far, which may have been
+    // renamed, by their path relative to `path`
+    let mut dirs = HashMap::new();
+    for entry in walk_calc(handle, path)? {
+      let (parent, file) = match entry.path.rfind('/') {
+        Some(idx) => (&dirs[&entry.path[..idx]], &entry.path[idx + 1..]),
+        None => (&base, entry.path.as_str()),
+      };
+      let name = unique_name(join(parent, &sanitize::file_name(file)?), &mut used);
+      if entry.is_dir {
+        dirs.insert(entry.path.clone(), name.clone());
+      }
+      entries.push(ExportEntry {
+        src: path.join(&entry.path)?.to_string(),
+        name,
+        is_dir: entry.is_dir,
+        size: entry.size,
+        date: entry.date,
+      });
+    }
+  }
+  Ok(entries)
+}
+
+/// Returns `name`, or the first of its alternatives that isn't in `used` yet,
+/// and marks it used. Names are compared ignoring case since some file
+/// systems do.
+fn unique_name(name: String, used: &mut HashSet<String>) -> String {
+  let mut candidate = name.clone();
+  let mut n = 1;
+  while !used.insert(candidate.to_lowercase()) {
+    candidate = conflict::renamed(&name, n);
+    n += 1;
+  }
+  candidate
+}
+
+/// Reads each entry from the calculator into a zip archive at `dest`, one
+/// file at a time. `progress` is called with the file being read and the
+/// number of bytes left in the whole export. The archive is removed if the
+/// export fails.
+pub fn write_zip(
+  handle: &libnspire::Handle<GlobalContext>,
+  entries: &[ExportEntry],
+  dest: &Path,
+  progress: &mut dyn FnMut(&str, usize),
+) -> anyhow::Result<ExportSummary> {
+  let res = (|| {
+    let file = BufWriter::new(File::create(dest)?);
+    let mut archive = ArchiveWriter::new(ArchiveFormat::Zip, file);
+    let summary = write_entries(handle, entries, &mut archive, progress)?;
+    archive.finish()?.flush()?;
+    Ok(summary)
+  })();
+  if res.is_err() {
+    let _ = fs::remove_file(dest);
+  }
+  res
+}
+
+fn write_entries(
+  handle: &libnspire::Handle<GlobalContext>,
+  entries: &[ExportEntry],
+  archive: &mut ArchiveWriter<BufWriter<File>>,
+  progress: &mut dyn FnMut(&str, usize),
+) -> anyhow::Result<ExportSummary> {
+  let mut summary = ExportSummary {
+    files: 0,
+    size: 0,
+    skipped: vec![],
+  };
+  let mut remaining: usize = entries.iter().map(|entry| entry.size as usize).sum();
+  for entry in entries {
+    if entry.is_dir {
+      archive.add_dir(&format!("{}/", entry.name), entry.date)?;
+      continue;
+    }
+    let mut buf = vec![0; entry.size as usize];
+    let before = remaining;
+    let res = handle.read_file(&entry.src, &mut buf, &mut |left| {
+      progress(
+        &entry.src,
+        before - (entry.size as usize).saturating_sub(left),
+      )
+    });
+    remaining -= entry.size as usize;
+    match res {
+      Ok(len) => buf.truncate(len),
+      Err(libnspire::Error::NoDevice) => return Err(libnspire::Error::NoDevice.into()),
+      Err(error) => {
+        summary.skipped.push(SkippedEntry {
+          path: entry.src.clone(),
+          error: error.to_string(),
+        });
+        continue;
+      }
+    }
+    archive.add_file(&entry.name, entry.date, &buf)?;
+    summary.files += 1;
+    summary.size += buf.len() as u64;
+  }
+  Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unique_names() {
+    let mut used = HashSet::new();
+    assert_eq!(unique_name("a.tns".into(), &mut used), "a.tns");
+    assert_eq!(unique_name("a.tns".into(), &mut used), "a_1.tns");
+    assert_eq!(unique_name("A.tns".into(), &mut used), "A_2.tns");
+    assert_eq!(unique_name("dir/a.tns".into(), &mut used), "dir/a.tns");
+    assert_eq!(unique_name("dir".into(), &mut used), "dir");
+    assert_eq!(unique_name("dir".into(), &mut used), "dir_1");
+  }
+}
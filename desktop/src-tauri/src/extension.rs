@@ -0,0 +1,96 @@
+use libnspire::info::Info;
+
+/// The extension the calculator shows documents with, without the leading dot.
+/// Files with any other extension are hidden on the handheld.
+pub fn doc_extension(info: &Info) -> &str {
+  match info.file_extension.trim_start_matches('.') {
+    "" => "tns",
+    ext => ext,
+  }
+}
+
+/// Whether the handheld will list a file with this name.
+pub fn is_visible(name: &str, ext: &str) -> bool {
+  match name.rfind('.') {
+    Some(idx) if idx > 0 => name[idx + 1..].eq_ignore_ascii_case(ext),
+    _ => false,
+  }
+}
+
+/// Appends `.ext` to a name the handheld would hide, so `game.lua` becomes
+/// `game.lua.tns`.
+pub fn add_extension(name: &str, ext: &str) -> String {
+  if is_visible(name, ext) {
+    name.to_string()
+  } else {
+    format!("{}.{}", name, ext)
+  }
+}
+
+/// Removes a trailing `.ext`, so `game.lua.tns` becomes `game.lua`. Names that
+/// would be left empty are kept as they are.
+pub fn strip_extension<'a>(name: &'a str, ext: &str) -> &'a str {
+  match name.rfind('.') {
+    Some(idx) if idx > 0 && is_visible(name, ext) => &name[..idx],
+    _ => name,
+  }
+}
+
+pub fn hidden_warning(name: &str, ext: &str) -> String {
+  format!(
+    "{} won't be shown on the calculator, which only lists .{} files",
+    name, ext
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reads_the_extension_from_the_device() {
+    let mut info = crate::os_image::tests::cx_ii_cas();
+    assert_eq!(doc_extension(&info), "tns");
+    info.file_extension = "tnc".to_string();
+    assert_eq!(doc_extension(&info), "tnc");
+    info.file_extension = String::new();
+    assert_eq!(doc_extension(&info), "tns");
+  }
+
+  #[test]
+  fn checks_visibility() {
+    assert!(is_visible("quiz.tns", "tns"));
+    assert!(is_visible("quiz.TNS", "tns"));
+    assert!(is_visible("game.lua.tns", "tns"));
+    assert!(!is_visible("game.lua", "tns"));
+    assert!(!is_visible("notes", "tns"));
+    assert!(!is_visible(".tns", "tns"));
+    assert!(!is_visible("quiz.tnsx", "tns"));
+  }
+
+  #[test]
+  fn adds_the_extension_once() {
+    assert_eq!(add_extension("game.lua", "tns"), "game.lua.tns");
+    assert_eq!(add_extension("notes", "tns"), "notes.tns");
+    assert_eq!(add_extension("quiz.tns", "tns"), "quiz.tns");
+    assert_eq!(add_extension("quiz.TNS", "tns"), "quiz.TNS");
+    assert_eq!(add_extension(".tns", "tns"), ".tns.tns");
+  }
+
+  #[test]
+  fn strips_the_extension() {
+    assert_eq!(strip_extension("game.lua.tns", "tns"), "game.lua");
+    assert_eq!(strip_extension("quiz.TNS", "tns"), "quiz");
+    assert_eq!(strip_extension("game.lua", "tns"), "game.lua");
+    assert_eq!(strip_extension("notes", "tns"), "notes");
+    assert_eq!(strip_extension(".tns", "tns"), ".tns");
+  }
+
+  #[test]
+  fn warns_about_hidden_files() {
+    assert_eq!(
+      hidden_warning("game.lua", "tns"),
+      "game.lua won't be shown on the calculator, which only lists .tns files"
+    );
+  }
+}
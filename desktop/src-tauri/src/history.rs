@@ -0,0 +1,372 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use libnspire::info::Info;
+use serde::{Deserialize, Serialize};
+
+use crate::backup::{format_time, now};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Operation {
+  Upload,
+  Download,
+  DownloadZip,
+  UploadOs,
+  Delete,
+  CreateDir,
+  Move,
+  Copy,
+  Sync,
+  Backup,
+  Restore,
+  Snapshot,
+}
+
+impl Operation {
+  pub fn name(self) -> &'static str {
+    match self {
+      Operation::Upload => "upload",
+      Operation::Download => "download",
+      Operation::DownloadZip => "download-zip",
+      Operation::UploadOs => "upload-os",
+      Operation::Delete => "delete",
+      Operation::CreateDir => "create-dir",
+      Operation::Move => "move",
+      Operation::Copy => "copy",
+      Operation::Sync => "sync",
+      Operation::Backup => "backup",
+      Operation::Restore => "restore",
+      Operation::Snapshot => "snapshot",
+    }
+  }
+}
+
+impl FromStr for Operation {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    [
+      Operation::Upload,
+      Operation::Download,
+      Operation::DownloadZip,
+      Operation::UploadOs,
+      Operation::Delete,
+      Operation::CreateDir,
+      Operation::Move,
+      Operation::Copy,
+      Operation::Sync,
+      Operation::Backup,
+      Operation::Restore,
+      Operation::Snapshot,
+    ]
+    .iter()
+    .copied()
+    .find(|op| op.name() == s)
+    .ok_or_else(|| format!("Unknown operation {}", s))
+  }
+}
+
+/// One line of the history log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+  /// When the operation finished, in seconds since the Unix epoch.
+  pub time: u64,
+  pub device_id: String,
+  pub device_name: String,
+  pub operation: Operation,
+  /// Where the data came from. Unset for operations that only act on one
+  /// path.
+  pub source: Option<String>,
+  /// The path that was written or changed. Deletes and created folders only
+  /// have a `dest`, which is the path that was deleted or created.
+  pub dest: Option<String>,
+  /// Bytes transferred, or 0 for operations that don't transfer any.
+  pub size: u64,
+  pub duration_ms: u64,
+  pub ok: bool,
+  pub error: Option<String>,
+}
+
+/// Where the log is kept unless another file is given.
+pub fn default_path() -> anyhow::Result<PathBuf> {
+  let data = dirs_next::data_dir()
+    .ok_or_else(|| anyhow::anyhow!("Couldn't find a data directory for the history"))?;
+  Ok(data.join("n-link").join("history.jsonl"))
+}
+
+/// An operation that has started and will be logged when it finishes.
+pub struct Pending {
+  device_id: String,
+  device_name: String,
+  operation: Operation,
+  source: Option<String>,
+  dest: Option<String>,
+  start: Instant,
+}
+
+pub fn start(
+  info: &Info,
+  operation: Operation,
+  source: Option<&str>,
+  dest: Option<&str>,
+) -> Pending {
+  Pending {
+    device_id: info.id.clone(),
+    device_name: info.name.clone(),
+    operation,
+    source: source.map(str::to_string),
+    dest: dest.map(str::to_string),
+    start: Instant::now(),
+  }
+}
+
+impl Pending {
+  /// Logs the outcome. A history that can't be written only gets a message
+  /// on stderr, since it shouldn't fail the operation itself.
+  pub fn finish<T, E: std::fmt::Display>(self, size: u64, res: &Result<T, E>) {
+    let entry = HistoryEntry {
+      time: now(),
+      device_id: self.device_id,
+      device_name: self.device_name,
+      operation: self.operation,
+      source: self.source,
+      dest: self.dest,
+      size,
+      duration_ms: self.start.elapsed().as_millis() as u64,
+      ok: res.is_ok(),
+      error: res.as_ref().err().map(|e| e.to_string()),
+    };
+    if let Err(error) = default_path().and_then(|path| append(&path, &entry)) {
+      eprintln!("Failed to write the history: {}", error);
+    }
+  }
+}
+
+pub fn append(path: &Path, entry: &HistoryEntry) -> anyhow::Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+  writeln!(file, "{}", serde_json::to_string(entry)?)?;
+  Ok(())
+}
+
+/// Which entries to return from [`read`]. Unset fields match everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryFilter {
+  pub device: Option<String>,
+  pub operation: Option<Operation>,
+  /// Only entries from this time on, in seconds since the Unix epoch.
+  pub since: Option<u64>,
+  /// Only entries whose source or destination contains this.
+  pub path: Option<String>,
+}
+
+impl HistoryFilter {
+  fn matches(&self, entry: &HistoryEntry) -> bool {
+    let device = match &self.device {
+      Some(device) => &entry.device_id == device || &entry.device_name == device,
+      None => true,
+    };
+    let path = match &self.path {
+      Some(path) => [&entry.source, &entry.dest]
+        .iter()
+        .any(|p| matches!(p, Some(p) if p.contains(path.as_str()))),
+      None => true,
+    };
+    let operation = match self.operation {
+      Some(operation) => operation == entry.operation,
+      None => true,
+    };
+    let since = match self.since {
+      Some(since) => entry.time >= since,
+      None => true,
+    };
+    device && path && operation && since
+  }
+}
+
+/// The entries matching `filter`, oldest first. Lines that can't be parsed,
+/// such as one cut short by a crash, are skipped.
+pub fn read(path: &Path, filter: &HistoryFilter) -> anyhow::Result<Vec<HistoryEntry>> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+    Err(e) => return Err(e.into()),
+  };
+  let mut entries = vec![];
+  for line in BufReader::new(file).lines() {
+    if let Ok(entry) = serde_json::from_str::<HistoryEntry>(&line?) {
+      if filter.matches(&entry) {
+        entries.push(entry);
+      }
+    }
+  }
+  Ok(entries)
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains(&[',', '"', '\n', '\r'][..]) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_string()
+  }
+}
+
+pub fn to_csv(entries: &[HistoryEntry]) -> String {
+  let mut csv =
+    String::from("time,device_id,device_name,operation,source,dest,size,duration_ms,ok,error\n");
+  for entry in entries {
+    let fields = [
+      format_time(entry.time),
+      entry.device_id.clone(),
+      entry.device_name.clone(),
+      entry.operation.name().to_string(),
+      entry.source.clone().unwrap_or_default(),
+      entry.dest.clone().unwrap_or_default(),
+      entry.size.to_string(),
+      entry.duration_ms.to_string(),
+      entry.ok.to_string(),
+      entry.error.clone().unwrap_or_default(),
+    ];
+    let line: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    csv.push_str(&line.join(","));
+    csv.push('\n');
+  }
+  csv
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(
+    time: u64,
+    operation: Operation,
+    source: Option<&str>,
+    dest: Option<&str>,
+  ) -> HistoryEntry {
+    HistoryEntry {
+      time,
+      device_id: "1234".to_string(),
+      device_name: "Calc".to_string(),
+      operation,
+      source: source.map(str::to_string),
+      dest: dest.map(str::to_string),
+      size: 10,
+      duration_ms: 5,
+      ok: true,
+      error: None,
+    }
+  }
+
+  #[test]
+  fn names_operations() {
+    assert_eq!("download-zip".parse(), Ok(Operation::DownloadZip));
+    assert_eq!(Operation::CreateDir.name(), "create-dir");
+    assert!("zip".parse::<Operation>().is_err());
+  }
+
+  #[test]
+  fn quotes_csv_fields() {
+    assert_eq!(csv_field("/documents/quiz.tns"), "/documents/quiz.tns");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+    assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+    assert_eq!(csv_field("line\rbreak"), "\"line\rbreak\"");
+    assert_eq!(csv_field(""), "");
+  }
+
+  #[test]
+  fn exports_csv() {
+    let mut failed = entry(0, Operation::Upload, Some("C:\\a, b.tns"), Some("/a.tns"));
+    failed.ok = false;
+    failed.error = Some("Device said \"no\"\nretry".to_string());
+    let csv = to_csv(&[entry(60, Operation::Delete, None, Some("/x")), failed]);
+    assert_eq!(
+      csv,
+      "time,device_id,device_name,operation,source,dest,size,duration_ms,ok,error\n\
+       1970-01-01 00:01:00,1234,Calc,delete,,/x,10,5,true,\n\
+       1970-01-01 00:00:00,1234,Calc,upload,\"C:\\a, b.tns\",/a.tns,10,5,false,\"Device said \"\"no\"\"\nretry\"\n"
+    );
+  }
+
+  #[test]
+  fn filters_entries() {
+    let upload = entry(
+      100,
+      Operation::Upload,
+      Some("/home/quiz.tns"),
+      Some("/documents/quiz.tns"),
+    );
+    let delete = entry(200, Operation::Delete, None, Some("/documents/old.tns"));
+    let matching = |filter: HistoryFilter| {
+      [&upload, &delete]
+        .iter()
+        .filter(|entry| filter.matches(entry))
+        .map(|entry| entry.time)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(matching(HistoryFilter::default()), vec![100, 200]);
+    assert_eq!(
+      matching(HistoryFilter {
+        operation: Some(Operation::Delete),
+        ..Default::default()
+      }),
+      vec![200]
+    );
+    assert_eq!(
+      matching(HistoryFilter {
+        since: Some(150),
+        ..Default::default()
+      }),
+      vec![200]
+    );
+    assert_eq!(
+      matching(HistoryFilter {
+        path: Some("quiz".to_string()),
+        ..Default::default()
+      }),
+      vec![100]
+    );
+    assert_eq!(
+      matching(HistoryFilter {
+        device: Some("Calc".to_string()),
+        ..Default::default()
+      }),
+      vec![100, 200]
+    );
+    assert_eq!(
+      matching(HistoryFilter {
+        device: Some("5678".to_string()),
+        ..Default::default()
+      }),
+      Vec::<u64>::new()
+    );
+  }
+
+  #[test]
+  fn skips_a_truncated_last_line() {
+    let path = std::env::temp_dir().join(format!("n-link-history-{}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    assert!(read(&path, &HistoryFilter::default()).unwrap().is_empty());
+    append(&path, &entry(1, Operation::Upload, None, Some("/a.tns"))).unwrap();
+    append(&path, &entry(2, Operation::Delete, None, Some("/b.tns"))).unwrap();
+    let line = serde_json::to_string(&entry(3, Operation::Copy, None, None)).unwrap();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    file.write_all(&line.as_bytes()[..line.len() / 2]).unwrap();
+    drop(file);
+    let entries = read(&path, &HistoryFilter::default()).unwrap();
+    assert_eq!(
+      entries.iter().map(|entry| entry.time).collect::<Vec<_>>(),
+      vec![1, 2]
+    );
+    fs::remove_file(path).unwrap();
+  }
+}
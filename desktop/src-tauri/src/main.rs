@@ -3,9 +3,9 @@
   windows_subsystem = "windows"
 )]
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use hashbrown::HashMap;
 use libnspire::{PID_CX2, VID};
@@ -13,10 +13,32 @@ use rusb::{GlobalContext, Hotplug, UsbContext};
 use serde::Serialize;
 use tauri::{Runtime, Window};
 
-use crate::cmd::{add_device, AddDevice, DevId, ProgressUpdate};
+use crate::cmd::{add_device, AddDevice, DevId, OperationKind, ProgressUpdate, RetryUpdate};
 
+mod backup;
 mod cli;
 mod cmd;
+mod conflict;
+mod crypt;
+mod diff;
+mod export;
+mod extension;
+mod history;
+mod nspire_path;
+mod os_image;
+mod os_install;
+mod os_library;
+mod remote_watch;
+mod restore;
+mod retry;
+mod sanitize;
+mod space;
+mod store;
+mod sync;
+mod term;
+mod tree;
+mod verify;
+mod watch;
 
 pub enum DeviceState {
   Open(
@@ -47,8 +69,16 @@ impl<R: Runtime> Hotplug<GlobalContext> for DeviceMon<R> {
       .map(|d| d.product_id() == PID_CX2)
       .unwrap_or(false);
     let device = Arc::new(device);
-    std::thread::spawn(move || loop {
-      match add_device(device.clone()) {
+    std::thread::spawn(move || {
+      let dev_id = DevId {
+        bus_number: device.bus_number(),
+        address: device.address(),
+      };
+      let added = retry::policy().run(
+        || add_device(device.clone()),
+        retry_sender(&handle, dev_id, "open"),
+      );
+      match added {
         Ok(dev) => {
           let name = (dev.1).name.clone();
           let needs_drivers = (dev.1).needs_drivers;
@@ -67,17 +97,11 @@ impl<R: Runtime> Hotplug<GlobalContext> for DeviceMon<R> {
           ) {
             eprintln!("{}", msg);
           };
-          return;
-        }
-        Err(rusb::Error::Busy) => {
-          println!("busy");
         }
         Err(e) => {
           eprintln!("{}", e);
-          return;
         }
       }
-      std::thread::sleep(Duration::from_millis(250));
     });
   }
 
@@ -117,35 +141,185 @@ fn err_wrap<T, R: Runtime>(
   res
 }
 
-fn progress_sender<R: Runtime>(
-  window: &Window<R>,
+/// Shortest time between two progress events of one operation.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+static NEXT_OPERATION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Sends `progress` events for one operation, at most once per
+/// [`PROGRESS_INTERVAL`] except for the first and last update of each file.
+struct ProgressReporter<'a, R: Runtime> {
+  window: &'a Window<R>,
   dev: DevId,
+  operation_id: u64,
+  kind: OperationKind,
+  path: Option<String>,
   total: usize,
-) -> impl FnMut(usize) + '_ {
-  let mut i = 0;
-  move |remaining| {
-    if i > 5 {
-      i = 0;
+  batch_index: Option<usize>,
+  batch_len: Option<usize>,
+  start: Instant,
+  last_sent: Option<Instant>,
+}
+
+impl<'a, R: Runtime> ProgressReporter<'a, R> {
+  fn new(window: &'a Window<R>, dev: DevId, kind: OperationKind, total: usize) -> Self {
+    ProgressReporter {
+      window,
+      dev,
+      operation_id: NEXT_OPERATION_ID.fetch_add(1, Ordering::Relaxed),
+      kind,
+      path: None,
+      total,
+      batch_index: None,
+      batch_len: None,
+      start: Instant::now(),
+      last_sent: None,
     }
-    if i == 0 || remaining == 0 {
-      if let Err(msg) = window.emit(
-        "progress",
-        ProgressUpdate {
-          dev,
-          remaining,
-          total,
-        },
-      ) {
-        eprintln!("{}", msg);
-      };
+  }
+
+  fn with_path(mut self, path: &str) -> Self {
+    self.path = Some(path.to_string());
+    self
+  }
+
+  fn with_batch_len(mut self, len: usize) -> Self {
+    self.batch_len = Some(len);
+    self
+  }
+
+  /// Moves on to the next file of a batch if `path` isn't the current one.
+  fn file(&mut self, path: &str) {
+    if self.path.as_deref() != Some(path) {
+      self.path = Some(path.to_string());
+      self.batch_index = Some(self.batch_index.unwrap_or(0) + 1);
+      self.last_sent = None;
     }
-    i += 1;
+  }
+
+  /// Starts the next file of a batch whose files are counted separately.
+  fn start_file(&mut self, path: &str, total: usize) {
+    self.file(path);
+    self.total = total;
+    self.start = Instant::now();
+  }
+
+  fn update(&mut self, remaining: usize) {
+    let now = Instant::now();
+    let due = match self.last_sent {
+      Some(last) => now.duration_since(last) >= PROGRESS_INTERVAL,
+      None => true,
+    };
+    if !due && remaining != 0 {
+      return;
+    }
+    self.last_sent = Some(now);
+    let done = self.total.saturating_sub(remaining) as f64;
+    let elapsed = now.duration_since(self.start).as_secs_f64();
+    let bytes_per_sec = if elapsed > 0.0 { done / elapsed } else { 0.0 };
+    let eta_secs = if bytes_per_sec > 0.0 {
+      Some((remaining as f64 / bytes_per_sec).ceil() as u64)
+    } else {
+      None
+    };
+    if let Err(msg) = self.window.emit(
+      "progress",
+      ProgressUpdate {
+        dev: self.dev,
+        operation_id: self.operation_id,
+        kind: self.kind,
+        path: self.path.clone(),
+        remaining,
+        total: self.total,
+        bytes_per_sec: bytes_per_sec as u64,
+        eta_secs,
+        batch_index: self.batch_index,
+        batch_len: self.batch_len,
+      },
+    ) {
+      eprintln!("{}", msg);
+    };
+  }
+}
+
+/// Reports the progress of a transfer of a single file.
+fn progress_sender<'a, R: Runtime>(
+  window: &'a Window<R>,
+  dev: DevId,
+  kind: OperationKind,
+  path: &str,
+  total: usize,
+) -> impl FnMut(usize) + 'a {
+  let mut reporter = ProgressReporter::new(window, dev, kind, total).with_path(path);
+  move |remaining| reporter.update(remaining)
+}
+
+/// Reports the progress of a batch whose callback gives the current file and
+/// the bytes left in the whole batch.
+fn batch_progress_sender<'a, R: Runtime>(
+  window: &'a Window<R>,
+  dev: DevId,
+  kind: OperationKind,
+  total: usize,
+  files: usize,
+) -> impl FnMut(&str, usize) + 'a {
+  let mut reporter = ProgressReporter::new(window, dev, kind, total).with_batch_len(files);
+  move |path, remaining| {
+    reporter.file(path);
+    reporter.update(remaining);
   }
 }
 
+/// Reports each retry of `operation` to the window.
+fn retry_sender<'a, R: Runtime, E: std::fmt::Display>(
+  window: &'a Window<R>,
+  dev: DevId,
+  operation: &'a str,
+) -> impl FnMut(u32, &E, Duration) + 'a {
+  let max_attempts = retry::policy().max_attempts;
+  move |attempt, error, delay| {
+    if let Err(msg) = window.emit(
+      "retry",
+      RetryUpdate {
+        dev,
+        operation: operation.to_string(),
+        attempt,
+        max_attempts,
+        error: error.to_string(),
+        delay_ms: delay.as_millis() as u64,
+      },
+    ) {
+      eprintln!("{}", msg);
+    };
+  }
+}
+
+/// Runs an idempotent operation with the current retry policy, reporting
+/// retries to the window.
+fn with_retry<T, R: Runtime>(
+  window: &Window<R>,
+  dev: DevId,
+  operation: &str,
+  op: impl FnMut() -> Result<T, libnspire::Error>,
+) -> Result<T, libnspire::Error> {
+  retry::policy().run(op, retry_sender(window, dev, operation))
+}
+
 fn get_open_dev(
   dev: &DevId,
 ) -> Result<Arc<Mutex<libnspire::Handle<GlobalContext>>>, anyhow::Error> {
+  if let Some(dev) = DEVICES.read().unwrap().get(&(dev.bus_number, dev.address)) {
+    if let DeviceState::Open(_, info) = &dev.state {
+      if info.run_level == libnspire::info::RunLevel::Recovery {
+        anyhow::bail!("The calculator needs an OS before it can do anything else");
+      }
+    }
+  }
+  get_os_dev(dev)
+}
+
+/// Like [`get_open_dev`], but also works in recovery mode, where the
+/// calculator can only receive an OS.
+fn get_os_dev(dev: &DevId) -> Result<Arc<Mutex<libnspire::Handle<GlobalContext>>>, anyhow::Error> {
   if let Some(dev) = DEVICES.read().unwrap().get(&(dev.bus_number, dev.address)) {
     match &dev.state {
       DeviceState::Open(handle, _) => Ok(handle.clone()),
@@ -156,6 +330,32 @@ fn get_open_dev(
   }
 }
 
+/// The info read from the device when it was opened.
+fn get_open_info(dev: &DevId) -> Result<libnspire::info::Info, anyhow::Error> {
+  if let Some(dev) = DEVICES.read().unwrap().get(&(dev.bus_number, dev.address)) {
+    match &dev.state {
+      DeviceState::Open(_, info) => Ok(info.clone()),
+      DeviceState::Closed => anyhow::bail!("Device closed"),
+    }
+  } else {
+    anyhow::bail!("Failed to find device");
+  }
+}
+
+/// Replaces the cached info of an open device, such as after a transfer
+/// changed its free storage.
+fn set_open_info(dev: &DevId, new_info: libnspire::info::Info) {
+  if let Some(device) = DEVICES
+    .write()
+    .unwrap()
+    .get_mut(&(dev.bus_number, dev.address))
+  {
+    if let DeviceState::Open(_, info) = &mut device.state {
+      *info = new_info;
+    }
+  }
+}
+
 #[derive(Serialize)]
 pub struct SerializedError(String);
 
@@ -166,22 +366,78 @@ impl<T: std::fmt::Display> From<T> for SerializedError {
 }
 
 mod invoked {
+  use std::collections::BTreeSet;
   use std::fs::File;
   use std::io::{Read, Write};
-  use std::path::PathBuf;
+  use std::path::{Path, PathBuf};
   use std::sync::{Arc, Mutex};
+  use std::time::Duration;
 
   use libnspire::dir::EntryType;
   use serde::Serialize;
   use tauri::{Runtime, Window};
 
-  use crate::cmd::{DevId, FileInfo};
-  use crate::{err_wrap, get_open_dev, progress_sender, DeviceState, SerializedError};
+  use crate::backup::{self, ArchiveFormat, BackupArchive};
+  use crate::cmd::{
+    BatchResult, DevId, FileInfo, InstallUpdate, OperationKind, SyncResult, TransferResult,
+    TransferStatus,
+  };
+  use crate::conflict::{self, ConflictPolicy, Resolution};
+  use crate::export;
+  use crate::extension;
+  use crate::history::{self, HistoryFilter, Operation};
+  use crate::nspire_path::NspirePath;
+  use crate::os_image::{self, OsImage};
+  use crate::os_install::{self, InstallPhase};
+  use crate::restore;
+  use crate::retry::{self, RetryPolicy};
+  use crate::sanitize;
+  use crate::space;
+  use crate::store::Store;
+  use crate::sync::{self, ActionKind, SyncMode, SyncOptions};
+  use crate::verify;
+  use crate::{
+    batch_progress_sender, err_wrap, get_open_dev, get_open_info, get_os_dev, progress_sender,
+    set_open_info, with_retry, DeviceState, ProgressReporter, SerializedError,
+  };
 
   use super::DEVICES;
 
+  /// Turns a verification into the `verified` and `verify_error` fields of a
+  /// [`TransferResult`]. Only a lost device is an error.
+  fn verify_result<R: Runtime>(
+    res: anyhow::Result<()>,
+    dev: DevId,
+    window: &Window<R>,
+  ) -> anyhow::Result<(Option<bool>, Option<String>)> {
+    match res {
+      Ok(()) => Ok((Some(true), None)),
+      Err(error) => {
+        if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+          err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, window)?;
+        }
+        Ok((Some(false), Some(error.to_string())))
+      }
+    }
+  }
+
+  fn unwritten_status<P>(resolution: Resolution<P>) -> TransferStatus {
+    match resolution {
+      Resolution::Ask => TransferStatus::Conflict,
+      _ => TransferStatus::Skipped,
+    }
+  }
+
   #[tauri::command]
-  pub fn open_device(bus_number: u8, address: u8) -> Result<impl Serialize, SerializedError> {
+  pub fn open_device<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
     let device = if let Some(dev) = DEVICES.read().unwrap().get(&(bus_number, address)) {
       if !matches!(dev.state, DeviceState::Closed) {
         return Err("Already open".into());
@@ -190,8 +446,10 @@ mod invoked {
     } else {
       return Err("Failed to find device".into());
     };
-    let handle = libnspire::Handle::new(device.open()?)?;
-    let info = handle.info()?;
+    let handle = with_retry(&window, dev, "open", || {
+      libnspire::Handle::new(device.open()?)
+    })?;
+    let info = with_retry(&window, dev, "info", || handle.info())?;
     {
       let mut guard = DEVICES.write().unwrap();
       let device = guard
@@ -202,6 +460,20 @@ mod invoked {
     Ok(info)
   }
 
+  #[tauri::command]
+  pub fn get_retry_policy() -> Result<impl Serialize, SerializedError> {
+    Ok(retry::policy())
+  }
+
+  #[tauri::command]
+  pub fn set_retry_policy(policy: RetryPolicy) -> Result<impl Serialize, SerializedError> {
+    if policy.max_attempts == 0 {
+      return Err("At least one attempt is needed".into());
+    }
+    retry::set_policy(policy);
+    Ok(())
+  }
+
   #[tauri::command]
   pub fn close_device(bus_number: u8, address: u8) -> Result<impl Serialize, SerializedError> {
     let mut guard = DEVICES.write().unwrap();
@@ -222,9 +494,14 @@ mod invoked {
       bus_number,
       address,
     };
-    let handle = get_open_dev(&dev)?;
+    let handle = get_os_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    let info = err_wrap(handle.info(), dev, &window)?;
+    let info = err_wrap(
+      with_retry(&window, dev, "info", || handle.info()),
+      dev,
+      &window,
+    )?;
+    set_open_info(&dev, info.clone());
     Ok(info)
   }
 
@@ -232,7 +509,7 @@ mod invoked {
   pub fn list_dir<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: String,
+    path: NspirePath,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -241,7 +518,11 @@ mod invoked {
     };
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    let dir = err_wrap(handle.list_dir(&path), dev, &window)?;
+    let dir = err_wrap(
+      with_retry(&window, dev, "list_dir", || handle.list_dir(&path)),
+      dev,
+      &window,
+    )?;
 
     Ok(
       dir
@@ -256,12 +537,94 @@ mod invoked {
     )
   }
 
+  /// How [`download`] writes a file.
+  struct DownloadOptions {
+    on_conflict: ConflictPolicy,
+    strip_extension: bool,
+    verify: bool,
+  }
+
+  /// Downloads `file` of `size` bytes into the folder `dest`.
+  #[allow(clippy::too_many_arguments)]
+  fn download<R: Runtime>(
+    handle: &libnspire::Handle<rusb::GlobalContext>,
+    info: &libnspire::info::Info,
+    dev: DevId,
+    window: &Window<R>,
+    file: &NspirePath,
+    size: u64,
+    dest: &Path,
+    options: &DownloadOptions,
+    progress: &mut dyn FnMut(usize),
+  ) -> anyhow::Result<TransferResult> {
+    let mut name = sanitize::calc_file_name(file)?;
+    if options.strip_extension {
+      name = extension::strip_extension(&name, extension::doc_extension(info)).to_string();
+    }
+    let attr = err_wrap(
+      with_retry(window, dev, "file_attr", || handle.file_attr(file)),
+      dev,
+      window,
+    )?;
+    let target = match conflict::resolve_local(dest.join(&name), attr.date(), options.on_conflict)?
+    {
+      Resolution::Write(target) => target,
+      resolution => {
+        return Ok(TransferResult {
+          path: dest.join(&name).to_string_lossy().to_string(),
+          status: unwritten_status(resolution),
+          warning: None,
+          verified: None,
+          verify_error: None,
+        })
+      }
+    };
+    let mut buf = vec![0; size as usize];
+    let pending = history::start(
+      info,
+      Operation::Download,
+      Some(file),
+      Some(&target.to_string_lossy()),
+    );
+    let res = err_wrap(
+      with_retry(window, dev, "read_file", || {
+        handle.read_file(file, &mut buf, &mut *progress)
+      }),
+      dev,
+      window,
+    )
+    .map_err(anyhow::Error::from)
+    .and_then(|len| {
+      buf.truncate(len);
+      File::create(&target)?.write_all(&buf)?;
+      Ok(len)
+    });
+    pending.finish(size, &res);
+    let len = res?;
+    let (verified, verify_error) = if options.verify {
+      verify_result(verify::verify_download(handle, file, len), dev, window)?
+    } else {
+      (None, None)
+    };
+    Ok(TransferResult {
+      path: target.to_string_lossy().to_string(),
+      status: TransferStatus::Written,
+      warning: None,
+      verified,
+      verify_error,
+    })
+  }
+
   #[tauri::command]
+  #[allow(clippy::too_many_arguments)]
   pub fn download_file<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: (String, u64),
+    path: (NspirePath, u64),
     dest: String,
+    on_conflict: Option<ConflictPolicy>,
+    strip_extension: Option<bool>,
+    verify: Option<bool>,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -269,31 +632,37 @@ mod invoked {
       address,
     };
     let (file, size) = path;
-    let dest = PathBuf::from(dest);
+    let info = get_open_info(&dev)?;
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    let mut buf = vec![0; size as usize];
-    err_wrap(
-      handle.read_file(
-        &file,
-        &mut buf,
-        &mut progress_sender(&window, dev, size as usize),
-      ),
+    let options = DownloadOptions {
+      on_conflict: on_conflict.unwrap_or(ConflictPolicy::Overwrite),
+      strip_extension: strip_extension.unwrap_or(false),
+      verify: verify.unwrap_or(false),
+    };
+    Ok(download(
+      &handle,
+      &info,
       dev,
       &window,
-    )?;
-    if let Some(name) = file.split('/').last() {
-      File::create(dest.join(name))?.write_all(&buf)?;
-    }
-    Ok(())
+      &file,
+      size,
+      &PathBuf::from(dest),
+      &options,
+      &mut progress_sender(&window, dev, OperationKind::Download, &file, size as usize),
+    )?)
   }
 
   #[tauri::command]
+  #[allow(clippy::too_many_arguments)]
   pub fn upload_file<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: String,
+    path: NspirePath,
     src: String,
+    on_conflict: Option<ConflictPolicy>,
+    add_extension: Option<bool>,
+    verify: Option<bool>,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -301,25 +670,225 @@ mod invoked {
       address,
     };
     let file = PathBuf::from(src);
+    let info = get_open_info(&dev)?;
+    let ext = extension::doc_extension(&info);
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
     let mut buf = vec![];
-    File::open(&file)?.read_to_end(&mut buf)?;
+    let mut f = File::open(&file)?;
+    let date = conflict::local_date(&f.metadata()?);
+    f.read_to_end(&mut buf)?;
     let name = file
       .file_name()
       .ok_or_else(|| anyhow::anyhow!("Failed to get file name"))?
       .to_string_lossy()
       .to_string();
-    err_wrap(
-      handle.write_file(
-        &format!("{}/{}", path, name),
-        &buf,
-        &mut progress_sender(&window, dev, buf.len()),
+    let mut warning = None;
+    let name = if add_extension.unwrap_or(false) {
+      extension::add_extension(&name, ext)
+    } else {
+      if !extension::is_visible(&name, ext) {
+        warning = Some(extension::hidden_warning(&name, ext));
+      }
+      name
+    };
+    let dest = path.join(&name)?.to_string();
+    let target = match err_wrap(
+      conflict::resolve_calc(
+        &handle,
+        dest.clone(),
+        date,
+        on_conflict.unwrap_or(ConflictPolicy::Overwrite),
       ),
       dev,
       &window,
+    )? {
+      Resolution::Write(target) => target,
+      resolution => {
+        return Ok(TransferResult {
+          path: dest,
+          status: unwritten_status(resolution),
+          warning,
+          verified: None,
+          verify_error: None,
+        })
+      }
+    };
+    let pending = history::start(
+      &info,
+      Operation::Upload,
+      Some(&file.to_string_lossy()),
+      Some(&target),
+    );
+    let res = space::check_upload(&handle, vec![(target.as_str(), buf.len() as u64)]).and_then(
+      |new_info| {
+        set_open_info(&dev, new_info);
+        Ok(err_wrap(
+          handle.write_file(
+            &target,
+            &buf,
+            &mut progress_sender(&window, dev, OperationKind::Upload, &target, buf.len()),
+          ),
+          dev,
+          &window,
+        )?)
+      },
+    );
+    pending.finish(buf.len() as u64, &res);
+    res?;
+    let (verified, verify_error) = if verify.unwrap_or(false) {
+      verify_result(verify::verify_upload(&handle, &target, &buf), dev, &window)?
+    } else {
+      (None, None)
+    };
+    Ok(TransferResult {
+      path: target,
+      status: TransferStatus::Written,
+      warning,
+      verified,
+      verify_error,
+    })
+  }
+
+  /// Checks that a batch of uploads to `path` fits on the calculator before
+  /// any of it is sent. Files replacing existing ones only count by how much
+  /// they grow.
+  #[tauri::command]
+  pub fn check_upload_space(
+    bus_number: u8,
+    address: u8,
+    path: NspirePath,
+    srcs: Vec<String>,
+    add_extension: Option<bool>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let info = get_open_info(&dev)?;
+    let ext = extension::doc_extension(&info);
+    let mut files = vec![];
+    for src in srcs {
+      let file = PathBuf::from(src);
+      let size = std::fs::metadata(&file)?.len();
+      let name = file
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get file name"))?
+        .to_string_lossy()
+        .to_string();
+      let name = if add_extension.unwrap_or(false) {
+        extension::add_extension(&name, ext)
+      } else {
+        name
+      };
+      files.push((path.join(&name)?.to_string(), size));
+    }
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let info = space::check_upload(
+      &handle,
+      files.iter().map(|(dest, size)| (dest.as_str(), *size)),
     )?;
-    Ok(())
+    set_open_info(&dev, info.clone());
+    Ok(info)
+  }
+
+  fn emit_install<R: Runtime>(
+    window: &Window<R>,
+    dev: DevId,
+    phase: InstallPhase,
+    message: Option<String>,
+  ) {
+    if let Err(msg) = window.emit(
+      "osInstall",
+      InstallUpdate {
+        dev,
+        phase,
+        message,
+      },
+    ) {
+      eprintln!("{}", msg);
+    };
+  }
+
+  #[derive(Serialize)]
+  #[serde(rename_all = "camelCase")]
+  pub struct OsCheck {
+    image: OsImage,
+    /// Why the image doesn't match the calculator. Empty if it does.
+    problems: Vec<String>,
+    /// Why installing it now is risky, such as a low battery. Empty if it
+    /// isn't.
+    preflight: Vec<String>,
+  }
+
+  fn check_os(
+    handle: &libnspire::Handle<rusb::GlobalContext>,
+    dev: &DevId,
+    src: &str,
+    buf: &[u8],
+  ) -> anyhow::Result<OsCheck> {
+    let name = PathBuf::from(src)
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let image = OsImage::parse(&name, buf)?;
+    let info = get_open_info(dev)?;
+    let problems = image.check(&info, handle.is_cx_ii()?);
+    let preflight = image.preflight(&info);
+    Ok(OsCheck {
+      image,
+      problems,
+      preflight,
+    })
+  }
+
+  /// Reads an OS file and reports whether it fits the calculator, so it can
+  /// be shown to the user before installing.
+  #[tauri::command]
+  pub fn inspect_os(
+    bus_number: u8,
+    address: u8,
+    src: String,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let handle = get_os_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let mut buf = vec![];
+    File::open(&src)?.read_to_end(&mut buf)?;
+    Ok(check_os(&handle, &dev, &src, &buf)?)
+  }
+
+  #[derive(Serialize)]
+  #[serde(rename_all = "camelCase")]
+  pub struct OsCandidate {
+    path: PathBuf,
+    image: OsImage,
+  }
+
+  /// Lists the OS images in `dir` that fit the calculator, newest first, to
+  /// pick one for a calculator in recovery mode.
+  #[tauri::command]
+  pub fn find_os_images(
+    bus_number: u8,
+    address: u8,
+    dir: String,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let is_cx_ii = get_os_dev(&dev)?.lock().unwrap().is_cx_ii()?;
+    let info = get_open_info(&dev)?;
+    Ok(
+      os_image::find_images(&PathBuf::from(dir), &info, is_cx_ii)?
+        .into_iter()
+        .map(|(path, image)| OsCandidate { path, image })
+        .collect::<Vec<_>>(),
+    )
   }
 
   #[tauri::command]
@@ -327,29 +896,72 @@ mod invoked {
     bus_number: u8,
     address: u8,
     src: String,
+    no_check_os: Option<bool>,
+    force: Option<bool>,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
       bus_number,
       address,
     };
-    let handle = get_open_dev(&dev)?;
+    let handle = get_os_dev(&dev)?;
     let handle = handle.lock().unwrap();
     let mut buf = vec![];
     File::open(&src)?.read_to_end(&mut buf)?;
-    err_wrap(
-      handle.send_os(&buf, &mut progress_sender(&window, dev, buf.len())),
+    let check = check_os(&handle, &dev, &src, &buf)?;
+    if !check.problems.is_empty() && !no_check_os.unwrap_or(false) {
+      return Err(check.problems.join("\n").into());
+    }
+    if !check.preflight.is_empty() && !force.unwrap_or(false) {
+      return Err(check.preflight.join("\n").into());
+    }
+    let info = get_open_info(&dev)?;
+    let id = info.id.clone();
+    let pending = history::start(&info, Operation::UploadOs, Some(&src), None);
+    emit_install(&window, dev, InstallPhase::Sending, None);
+    let sent = err_wrap(
+      handle.send_os(
+        &buf,
+        &mut progress_sender(&window, dev, OperationKind::UploadOs, &src, buf.len()),
+      ),
       dev,
       &window,
-    )?;
-    Ok(())
+    );
+    if let Err(e) = sent {
+      pending.finish(buf.len() as u64, &Err::<(), _>(&e));
+      emit_install(&window, dev, InstallPhase::Failed, Some(e.to_string()));
+      return Err(e.into());
+    }
+    let size = buf.len() as u64;
+    emit_install(&window, dev, InstallPhase::Installing, None);
+    let image = check.image.clone();
+    std::thread::spawn(move || {
+      let installed = os_install::wait_for_reboot(&id, (bus_number, address), |phase| {
+        emit_install(&window, dev, phase, None)
+      })
+      .and_then(|info| {
+        os_install::verify(&image, &info)?;
+        Ok(info)
+      });
+      pending.finish(size, &installed);
+      match installed {
+        Ok(info) => emit_install(
+          &window,
+          dev,
+          InstallPhase::Verified,
+          Some(info.version.to_string()),
+        ),
+        Err(e) => emit_install(&window, dev, InstallPhase::Failed, Some(e.to_string())),
+      }
+    });
+    Ok(check.image)
   }
 
   #[tauri::command]
   pub fn delete_file<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: String,
+    path: NspirePath,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -358,7 +970,10 @@ mod invoked {
     };
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    err_wrap(handle.delete_file(&path), dev, &window)?;
+    let pending = history::start(&get_open_info(&dev)?, Operation::Delete, None, Some(&path));
+    let res = err_wrap(handle.delete_file(&path), dev, &window);
+    pending.finish(0, &res);
+    res?;
     Ok(())
   }
 
@@ -366,7 +981,7 @@ mod invoked {
   pub fn delete_dir<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: String,
+    path: NspirePath,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -375,7 +990,10 @@ mod invoked {
     };
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    err_wrap(handle.delete_dir(&path), dev, &window)?;
+    let pending = history::start(&get_open_info(&dev)?, Operation::Delete, None, Some(&path));
+    let res = err_wrap(handle.delete_dir(&path), dev, &window);
+    pending.finish(0, &res);
+    res?;
     Ok(())
   }
 
@@ -383,7 +1001,7 @@ mod invoked {
   pub fn create_nspire_dir<R: Runtime>(
     bus_number: u8,
     address: u8,
-    path: String,
+    path: NspirePath,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -392,7 +1010,15 @@ mod invoked {
     };
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    err_wrap(handle.create_dir(&path), dev, &window)?;
+    let pending = history::start(
+      &get_open_info(&dev)?,
+      Operation::CreateDir,
+      None,
+      Some(&path),
+    );
+    let res = err_wrap(handle.create_dir(&path), dev, &window);
+    pending.finish(0, &res);
+    res?;
     Ok(())
   }
 
@@ -400,8 +1026,8 @@ mod invoked {
   pub fn move_file<R: Runtime>(
     bus_number: u8,
     address: u8,
-    src: String,
-    dest: String,
+    src: NspirePath,
+    dest: NspirePath,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
@@ -410,7 +1036,15 @@ mod invoked {
     };
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    err_wrap(handle.move_file(&src, &dest), dev, &window)?;
+    let pending = history::start(
+      &get_open_info(&dev)?,
+      Operation::Move,
+      Some(&src),
+      Some(&dest),
+    );
+    let res = err_wrap(handle.move_file(&src, &dest), dev, &window);
+    pending.finish(0, &res);
+    res?;
     Ok(())
   }
 
@@ -418,19 +1052,628 @@ mod invoked {
   pub fn copy<R: Runtime>(
     bus_number: u8,
     address: u8,
-    src: String,
+    src: NspirePath,
+    dest: NspirePath,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let pending = history::start(
+      &get_open_info(&dev)?,
+      Operation::Copy,
+      Some(&src),
+      Some(&dest),
+    );
+    let res = err_wrap(handle.copy_file(&src, &dest), dev, &window);
+    pending.finish(0, &res);
+    res?;
+    Ok(())
+  }
+
+  /// Runs `op` on each item of a batch and collects a result for each,
+  /// reporting progress across the whole batch. `describe` gives the path of
+  /// an item and its size in bytes, or 1 for items that aren't transfers.
+  /// `op` reports a lost device with [`err_wrap`], which stops the batch.
+  fn run_batch<R: Runtime, I, T>(
+    window: &Window<R>,
+    dev: DevId,
+    kind: OperationKind,
+    items: &[I],
+    describe: impl Fn(&I) -> (String, usize),
+    mut op: impl FnMut(&I, &mut dyn FnMut(usize)) -> anyhow::Result<T>,
+  ) -> Result<Vec<BatchResult<T>>, SerializedError> {
+    let total = items.iter().map(|item| describe(item).1).sum();
+    let mut reporter = ProgressReporter::new(window, dev, kind, total).with_batch_len(items.len());
+    let mut done = 0;
+    let mut results = vec![];
+    for item in items {
+      let (path, size) = describe(item);
+      reporter.file(&path);
+      let res = op(item, &mut |remaining| {
+        reporter.update(total - done - size + remaining.min(size))
+      });
+      done += size;
+      reporter.update(total - done);
+      match res {
+        Ok(result) => results.push(BatchResult {
+          path,
+          result: Some(result),
+          error: None,
+        }),
+        Err(error) => {
+          if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+            return Err(error.into());
+          }
+          results.push(BatchResult {
+            path,
+            result: None,
+            error: Some(error.to_string()),
+          });
+        }
+      }
+    }
+    Ok(results)
+  }
+
+  /// Deletes files and directories, including everything in the
+  /// directories.
+  #[tauri::command]
+  pub fn delete_many<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    paths: Vec<NspirePath>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let info = get_open_info(&dev)?;
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    run_batch(
+      &window,
+      dev,
+      OperationKind::Delete,
+      &paths,
+      |path| (path.to_string(), 1),
+      |path, _| {
+        let pending = history::start(&info, Operation::Delete, None, Some(path));
+        let res = err_wrap(crate::tree::delete_calc(&handle, path), dev, &window);
+        pending.finish(0, &res);
+        Ok(res?)
+      },
+    )
+  }
+
+  /// Downloads files into the folder `dest`, like [`download_file`] for each.
+  #[tauri::command]
+  #[allow(clippy::too_many_arguments)]
+  pub fn download_many<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    paths: Vec<(NspirePath, u64)>,
     dest: String,
+    on_conflict: Option<ConflictPolicy>,
+    strip_extension: Option<bool>,
+    verify: Option<bool>,
     window: Window<R>,
   ) -> Result<impl Serialize, SerializedError> {
     let dev = DevId {
       bus_number,
       address,
     };
+    let dest = PathBuf::from(dest);
+    let info = get_open_info(&dev)?;
     let handle = get_open_dev(&dev)?;
     let handle = handle.lock().unwrap();
-    err_wrap(handle.copy_file(&src, &dest), dev, &window)?;
+    let options = DownloadOptions {
+      on_conflict: on_conflict.unwrap_or(ConflictPolicy::Overwrite),
+      strip_extension: strip_extension.unwrap_or(false),
+      verify: verify.unwrap_or(false),
+    };
+    run_batch(
+      &window,
+      dev,
+      OperationKind::Download,
+      &paths,
+      |(file, size)| (file.to_string(), *size as usize),
+      |(file, size), progress| {
+        download(
+          &handle, &info, dev, &window, file, *size, &dest, &options, progress,
+        )
+      },
+    )
+  }
+
+  /// Copies or moves each `(src, dest)` pair.
+  fn copy_or_move_many<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    items: Vec<(NspirePath, NspirePath)>,
+    operation: Operation,
+    window: Window<R>,
+  ) -> Result<Vec<BatchResult<()>>, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let info = get_open_info(&dev)?;
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let kind = match operation {
+      Operation::Move => OperationKind::Move,
+      _ => OperationKind::Copy,
+    };
+    run_batch(
+      &window,
+      dev,
+      kind,
+      &items,
+      |(src, _)| (src.to_string(), 1),
+      |(src, dest), _| {
+        let pending = history::start(&info, operation, Some(src), Some(dest));
+        let res = match operation {
+          Operation::Move => handle.move_file(src, dest),
+          _ => handle.copy_file(src, dest),
+        };
+        let res = err_wrap(res, dev, &window);
+        pending.finish(0, &res);
+        Ok(res?)
+      },
+    )
+  }
+
+  #[tauri::command]
+  pub fn copy_many<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    items: Vec<(NspirePath, NspirePath)>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    copy_or_move_many(bus_number, address, items, Operation::Copy, window)
+  }
+
+  #[tauri::command]
+  pub fn move_many<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    items: Vec<(NspirePath, NspirePath)>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    copy_or_move_many(bus_number, address, items, Operation::Move, window)
+  }
+
+  /// Recursively lists `path`, with full paths. Directories are listed before
+  /// their contents.
+  #[tauri::command]
+  pub fn list_tree<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    path: NspirePath,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let entries = err_wrap(
+      with_retry(&window, dev, "list_tree", || {
+        crate::tree::walk_calc(&handle, &path)
+      }),
+      dev,
+      &window,
+    )?;
+    Ok(
+      entries
+        .into_iter()
+        .map(|entry| FileInfo {
+          path: crate::tree::join(&path, &entry.path),
+          is_dir: entry.is_dir,
+          date: entry.date,
+          size: entry.size,
+        })
+        .collect::<Vec<_>>(),
+    )
+  }
+
+  #[tauri::command]
+  #[allow(clippy::too_many_arguments)]
+  pub fn sync_folder<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    local: String,
+    remote: NspirePath,
+    mode: SyncMode,
+    delete: bool,
+    exclude: Vec<String>,
+    dry_run: bool,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let local = PathBuf::from(local);
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let options = SyncOptions {
+      mode,
+      delete,
+      exclude,
+    };
+    let mut plan = sync::plan(&handle, &local, &remote, &options)?;
+    let mut results = vec![];
+    let mut skipped = BTreeSet::new();
+    let mut lost = false;
+    let mut reporter = ProgressReporter::new(&window, dev, OperationKind::Sync, 0)
+      .with_batch_len(plan.actions.len());
+    let pending = history::start(
+      &get_open_info(&dev)?,
+      Operation::Sync,
+      Some(&local.to_string_lossy()),
+      Some(&remote),
+    );
+    for action in plan.actions.clone() {
+      reporter.start_file(&action.path, action.size as usize);
+      if dry_run || action.kind == ActionKind::Conflict {
+        skipped.insert(action.path.clone());
+        results.push(SyncResult {
+          action,
+          error: None,
+        });
+        continue;
+      }
+      let res = sync::apply(
+        &handle,
+        &local,
+        &remote,
+        &mut plan,
+        &action,
+        &mut |remaining| reporter.update(remaining),
+      );
+      lost = matches!(
+        res.as_ref().err().and_then(|error| error.downcast_ref()),
+        Some(libnspire::Error::NoDevice)
+      );
+      if res.is_err() {
+        skipped.insert(action.path.clone());
+      }
+      results.push(SyncResult {
+        action,
+        error: res.err().map(|e| e.to_string()),
+      });
+      if lost {
+        break;
+      }
+    }
+    if !dry_run {
+      let failed = results.iter().filter(|res| res.error.is_some()).count();
+      let size = results
+        .iter()
+        .filter(|res| !skipped.contains(&res.action.path))
+        .map(|res| res.action.size)
+        .sum();
+      let outcome = if lost {
+        Err(format!(
+          "The calculator was disconnected after {} of {} changes",
+          results.len() - 1,
+          plan.actions.len()
+        ))
+      } else if failed == 0 {
+        Ok(())
+      } else {
+        Err(format!("{} of {} changes failed", failed, results.len()))
+      };
+      pending.finish(size, &outcome);
+      if lost {
+        // The state can't be saved without listing the calculator. Changes
+        // made so far show up as differing on the next sync, which copies
+        // them over again at worst.
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, &window)?;
+      }
+      sync::save_state(&handle, &local, &remote, plan, &skipped, &options.exclude)?;
+    }
+    Ok(results)
+  }
+
+  #[tauri::command]
+  pub fn backup_device<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    dest: String,
+    root: Option<NspirePath>,
+    passphrase: Option<String>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let dest = PathBuf::from(dest);
+    let root = root.unwrap_or_else(NspirePath::root);
+    let format = ArchiveFormat::from_path(&dest)?;
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let info = err_wrap(handle.info(), dev, &window)?;
+    let entries = err_wrap(crate::tree::walk_calc(&handle, &root), dev, &window)?;
+    let total = entries.iter().map(|entry| entry.size as usize).sum();
+    let files = entries.iter().filter(|entry| !entry.is_dir).count();
+    let pending = history::start(
+      &info,
+      Operation::Backup,
+      Some(&root),
+      Some(&dest.to_string_lossy()),
+    );
+    let saved = backup::write_archive(&dest, format, passphrase.as_deref(), |archive| {
+      backup::write_backup(
+        &handle,
+        &info,
+        &root,
+        &entries,
+        archive,
+        &mut batch_progress_sender(&window, dev, OperationKind::Backup, total, files),
+      )
+    });
+    pending.finish(total as u64, &saved);
+    if let Err(error) = &saved {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, &window)?;
+      }
+    }
+    Ok(saved?)
+  }
+
+  /// Downloads files and directories into a new zip archive at `dest`.
+  /// Directories are stored with everything in them.
+  #[tauri::command]
+  pub fn download_zip<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    paths: Vec<NspirePath>,
+    dest: String,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let dest = PathBuf::from(dest);
+    let info = get_open_info(&dev)?;
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let entries = export::plan(&handle, &paths);
+    if let Err(error) = &entries {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, &window)?;
+      }
+    }
+    let entries = entries?;
+    let total = entries.iter().map(|entry| entry.size as usize).sum();
+    let files = entries.iter().filter(|entry| !entry.is_dir).count();
+    let pending = history::start(
+      &info,
+      Operation::DownloadZip,
+      Some(
+        &paths
+          .iter()
+          .map(|path| path.to_string())
+          .collect::<Vec<_>>()
+          .join(", "),
+      ),
+      Some(&dest.to_string_lossy()),
+    );
+    let summary = export::write_zip(
+      &handle,
+      &entries,
+      &dest,
+      &mut batch_progress_sender(&window, dev, OperationKind::DownloadZip, total, files),
+    );
+    pending.finish(total as u64, &summary);
+    if let Err(error) = &summary {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, &window)?;
+      }
+    }
+    Ok(summary?)
+  }
+
+  fn restore_archive<R: Runtime>(
+    dev: DevId,
+    archive: &BackupArchive,
+    root: Option<NspirePath>,
+    on_conflict: ConflictPolicy,
+    source: &str,
+    window: &Window<R>,
+  ) -> Result<Vec<restore::RestoreResult>, SerializedError> {
+    let root = match root {
+      Some(root) => root,
+      None => NspirePath::parse(&archive.manifest.root)?,
+    };
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let info = err_wrap(handle.info(), dev, window)?;
+    let plan = restore::plan(&handle, archive, &root, on_conflict)?;
+    restore::check_space(&info, &plan)?;
+    let total = plan
+      .items
+      .iter()
+      .filter(|item| item.action.writes())
+      .map(|item| item.size as usize)
+      .sum();
+    let files = plan
+      .items
+      .iter()
+      .filter(|item| item.action.writes())
+      .count();
+    let pending = history::start(&info, Operation::Restore, Some(source), Some(&root));
+    let results = restore::restore(
+      &handle,
+      archive,
+      &plan,
+      &mut batch_progress_sender(window, dev, OperationKind::Restore, total, files),
+    );
+    if let Err(error) = &results {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, window)?;
+      }
+    }
+    let outcome = match &results {
+      Ok(results) => match results.iter().filter(|res| res.error.is_some()).count() {
+        0 => Ok(()),
+        failed => Err(format!("{} of {} files failed", failed, results.len())),
+      },
+      Err(error) => Err(error.to_string()),
+    };
+    pending.finish(total as u64, &outcome);
+    Ok(results?)
+  }
+
+  #[tauri::command]
+  pub fn restore_device<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    src: String,
+    root: Option<NspirePath>,
+    on_conflict: ConflictPolicy,
+    passphrase: Option<String>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let archive = BackupArchive::open(&PathBuf::from(&src), passphrase.as_deref())?;
+    restore_archive(dev, &archive, root, on_conflict, &src, &window)
+  }
+
+  #[tauri::command]
+  pub fn snapshot_device<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    store: String,
+    root: Option<NspirePath>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let store_dir = store;
+    let store = Store::open(&PathBuf::from(&store_dir))?;
+    let root = root.unwrap_or_else(NspirePath::root);
+    let handle = get_open_dev(&dev)?;
+    let handle = handle.lock().unwrap();
+    let info = err_wrap(handle.info(), dev, &window)?;
+    let entries = err_wrap(crate::tree::walk_calc(&handle, &root), dev, &window)?;
+    let total = store.to_read(&info.id, &root, &entries)?;
+    let pending = history::start(&info, Operation::Snapshot, Some(&root), Some(&store_dir));
+    let mut progress = ProgressReporter::new(&window, dev, OperationKind::Snapshot, total);
+    let res = store.backup(&handle, &info, &root, &entries, &mut |path, remaining| {
+      progress.file(path);
+      progress.update(remaining)
+    });
+    pending.finish(total as u64, &res);
+    if let Err(error) = &res {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        err_wrap::<(), _>(Err(libnspire::Error::NoDevice), dev, &window)?;
+      }
+    }
+    Ok(res?)
+  }
+
+  #[tauri::command]
+  pub fn list_snapshots(
+    store: String,
+    device_id: Option<String>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let store = Store::open(&PathBuf::from(store))?;
+    Ok(store.snapshots(device_id.as_deref())?)
+  }
+
+  #[tauri::command]
+  pub fn prune_snapshots(
+    store: String,
+    device_id: Option<String>,
+    keep: usize,
+  ) -> Result<impl Serialize, SerializedError> {
+    let store = Store::open(&PathBuf::from(store))?;
+    Ok(store.prune(device_id.as_deref(), keep)?)
+  }
+
+  #[tauri::command]
+  #[allow(clippy::too_many_arguments)]
+  pub fn restore_snapshot<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    store: String,
+    device_id: String,
+    created: u64,
+    root: Option<NspirePath>,
+    on_conflict: ConflictPolicy,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    let source = format!("{} ({})", store, backup::format_time(created));
+    let store = Store::open(&PathBuf::from(store))?;
+    let archive = store.archive(store.snapshot(&device_id, created)?)?;
+    restore_archive(dev, &archive, root, on_conflict, &source, &window)
+  }
+
+  /// Returns the logged operations matching `filter`, oldest first, and
+  /// also writes them to `csv` if it is given.
+  #[tauri::command]
+  pub fn get_history(
+    filter: Option<HistoryFilter>,
+    csv: Option<String>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let entries = history::read(&history::default_path()?, &filter.unwrap_or_default())?;
+    if let Some(csv) = csv {
+      std::fs::write(csv, history::to_csv(&entries))?;
+    }
+    Ok(entries)
+  }
+
+  #[tauri::command]
+  pub fn watch_remote<R: Runtime>(
+    bus_number: u8,
+    address: u8,
+    path: NspirePath,
+    interval_ms: Option<u64>,
+    dest: Option<String>,
+    window: Window<R>,
+  ) -> Result<impl Serialize, SerializedError> {
+    let dev = DevId {
+      bus_number,
+      address,
+    };
+    get_open_dev(&dev)?;
+    crate::remote_watch::start(
+      dev,
+      path.into(),
+      Duration::from_millis(interval_ms.unwrap_or(2000)),
+      dest.map(PathBuf::from),
+      window,
+    );
     Ok(())
   }
+
+  #[tauri::command]
+  pub fn unwatch_remote(bus_number: u8, address: u8) -> Result<impl Serialize, SerializedError> {
+    Ok(crate::remote_watch::stop(DevId {
+      bus_number,
+      address,
+    }))
+  }
 }
 
 fn main() {
@@ -462,16 +1705,37 @@ fn main() {
       cmd::enumerate,
       invoked::open_device,
       invoked::close_device,
+      invoked::get_retry_policy,
+      invoked::set_retry_policy,
       invoked::update_device,
       invoked::list_dir,
       invoked::download_file,
       invoked::upload_file,
+      invoked::check_upload_space,
+      invoked::inspect_os,
+      invoked::find_os_images,
       invoked::upload_os,
       invoked::delete_file,
       invoked::delete_dir,
       invoked::create_nspire_dir,
       invoked::move_file,
       invoked::copy,
+      invoked::delete_many,
+      invoked::download_many,
+      invoked::copy_many,
+      invoked::move_many,
+      invoked::list_tree,
+      invoked::sync_folder,
+      invoked::backup_device,
+      invoked::download_zip,
+      invoked::restore_device,
+      invoked::snapshot_device,
+      invoked::list_snapshots,
+      invoked::prune_snapshots,
+      invoked::restore_snapshot,
+      invoked::get_history,
+      invoked::watch_remote,
+      invoked::unwatch_remote,
     ])
     .run(tauri::generate_context!())
     .expect("error while running tauri application");
@@ -0,0 +1,281 @@
+use std::convert::TryFrom;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Longest path the calculator accepts, in bytes.
+pub const MAX_LEN: usize = 254;
+
+/// Characters the calculator refuses in file and folder names.
+const INVALID_CHARS: &[char] = &['\\', ':', '*', '?', '"', '<', '>', '|'];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PathError {
+  InvalidChar(char, String),
+  TooLong(String),
+  /// A `..` segment would go above `/`.
+  EscapesRoot(String),
+}
+
+impl std::fmt::Display for PathError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PathError::InvalidChar(c, name) => {
+        write!(
+          f,
+          "{:?} can't be used in calculator file names ({})",
+          c, name
+        )
+      }
+      PathError::TooLong(path) => write!(
+        f,
+        "{} is longer than the {} characters the calculator allows",
+        path, MAX_LEN
+      ),
+      PathError::EscapesRoot(path) => write!(f, "{} points above the root folder", path),
+    }
+  }
+}
+
+impl std::error::Error for PathError {}
+
+/// A normalized, absolute path on the calculator: it always starts with `/`,
+/// never ends with one (except for the root itself), and has no empty, `.` or
+/// `..` segments.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NspirePath(String);
+
+impl NspirePath {
+  pub fn root() -> Self {
+    NspirePath("/".to_string())
+  }
+
+  /// Normalizes and validates a path. Relative paths are taken relative to
+  /// the root.
+  pub fn parse(path: &str) -> Result<Self, PathError> {
+    NspirePath::root().join(path)
+  }
+
+  /// Appends `rel`, which may contain several segments, to the path. A `rel`
+  /// starting with `/` replaces the path entirely.
+  pub fn join(&self, rel: &str) -> Result<Self, PathError> {
+    let mut parts: Vec<&str> = if rel.starts_with('/') {
+      vec![]
+    } else {
+      self.segments().collect()
+    };
+    for part in rel.split('/') {
+      match part {
+        "" | "." => {}
+        ".." => {
+          if parts.pop().is_none() {
+            return Err(PathError::EscapesRoot(rel.to_string()));
+          }
+        }
+        name => {
+          if let Some(c) = name
+            .chars()
+            .find(|c| c.is_control() || INVALID_CHARS.contains(c))
+          {
+            return Err(PathError::InvalidChar(c, name.to_string()));
+          }
+          parts.push(name);
+        }
+      }
+    }
+    let path = format!("/{}", parts.join("/"));
+    if path.len() > MAX_LEN {
+      return Err(PathError::TooLong(path));
+    }
+    Ok(NspirePath(path))
+  }
+
+  pub fn is_root(&self) -> bool {
+    self.0 == "/"
+  }
+
+  fn segments(&self) -> impl Iterator<Item = &str> {
+    self.0.split('/').filter(|part| !part.is_empty())
+  }
+
+  /// The containing folder, or `None` for the root.
+  pub fn parent(&self) -> Option<Self> {
+    if self.is_root() {
+      return None;
+    }
+    let idx = self.0.rfind('/').unwrap();
+    Some(NspirePath(if idx == 0 {
+      "/".to_string()
+    } else {
+      self.0[..idx].to_string()
+    }))
+  }
+
+  /// The last segment, or `None` for the root.
+  pub fn file_name(&self) -> Option<&str> {
+    self.0.rsplit('/').next().filter(|name| !name.is_empty())
+  }
+
+  /// Everything after the last `.` of the file name, if it isn't the first
+  /// character.
+  pub fn extension(&self) -> Option<&str> {
+    let name = self.file_name()?;
+    match name.rfind('.') {
+      Some(idx) if idx > 0 => Some(&name[idx + 1..]),
+      _ => None,
+    }
+  }
+}
+
+impl Deref for NspirePath {
+  type Target = str;
+
+  fn deref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl AsRef<str> for NspirePath {
+  fn as_ref(&self) -> &str {
+    &self.0
+  }
+}
+
+impl std::fmt::Display for NspirePath {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl FromStr for NspirePath {
+  type Err = PathError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    NspirePath::parse(s)
+  }
+}
+
+impl TryFrom<String> for NspirePath {
+  type Error = PathError;
+
+  fn try_from(s: String) -> Result<Self, Self::Error> {
+    NspirePath::parse(&s)
+  }
+}
+
+impl From<NspirePath> for String {
+  fn from(path: NspirePath) -> Self {
+    path.0
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(path: &str) -> String {
+    NspirePath::parse(path).unwrap().to_string()
+  }
+
+  #[test]
+  fn normalizes_slashes_and_dots() {
+    assert_eq!(parse("documents//quiz.tns"), "/documents/quiz.tns");
+    assert_eq!(parse("/documents/"), "/documents");
+    assert_eq!(parse("/documents/./a/../quiz.tns"), "/documents/quiz.tns");
+  }
+
+  #[test]
+  fn treats_empty_input_as_root() {
+    assert_eq!(parse(""), "/");
+    assert_eq!(parse("/"), "/");
+    assert_eq!(parse("//"), "/");
+    assert!(NspirePath::parse("").unwrap().is_root());
+    assert!(!NspirePath::parse("/a").unwrap().is_root());
+    assert_eq!(NspirePath::root().file_name(), None);
+  }
+
+  #[test]
+  fn rejects_escaping_the_root() {
+    assert_eq!(
+      NspirePath::parse("/.."),
+      Err(PathError::EscapesRoot("/..".to_string()))
+    );
+    assert!(NspirePath::parse("a/../..").is_err());
+    assert_eq!(parse("a/.."), "/");
+  }
+
+  #[test]
+  fn rejects_invalid_characters() {
+    assert_eq!(
+      NspirePath::parse("/a/b:c"),
+      Err(PathError::InvalidChar(':', "b:c".to_string()))
+    );
+    assert!(NspirePath::parse("/my quiz (2).tns").is_ok());
+    assert!(NspirePath::parse("/a\\b").is_err());
+    assert!(NspirePath::parse("/a\u{7}b").is_err());
+  }
+
+  #[test]
+  fn joins_relative_and_absolute_paths() {
+    let dir = NspirePath::parse("/documents/class").unwrap();
+    assert_eq!(
+      dir.join("quiz.tns").unwrap().to_string(),
+      "/documents/class/quiz.tns"
+    );
+    assert_eq!(
+      dir.join("../other/").unwrap().to_string(),
+      "/documents/other"
+    );
+    assert_eq!(dir.join("/examples").unwrap().to_string(), "/examples");
+    assert_eq!(dir.join("").unwrap(), dir);
+    assert_eq!(dir.join("quiz.tns").unwrap().file_name(), Some("quiz.tns"));
+  }
+
+  #[test]
+  fn finds_the_parent() {
+    let parent = |path: &str| NspirePath::parse(path).unwrap().parent();
+    assert_eq!(
+      parent("/documents/quiz.tns"),
+      Some(NspirePath::parse("/documents").unwrap())
+    );
+    assert_eq!(parent("/documents"), Some(NspirePath::root()));
+    assert_eq!(parent("/"), None);
+  }
+
+  #[test]
+  fn finds_the_extension() {
+    let extension = |path: &str| {
+      NspirePath::parse(path)
+        .unwrap()
+        .extension()
+        .map(str::to_string)
+    };
+    assert_eq!(extension("/documents/quiz.tns"), Some("tns".to_string()));
+    assert_eq!(extension("/a.b/quiz.tar.tns"), Some("tns".to_string()));
+    assert_eq!(extension("/a.b/quiz"), None);
+    assert_eq!(extension("/.hidden"), None);
+    assert_eq!(extension("/quiz."), Some(String::new()));
+    assert_eq!(extension("/"), None);
+  }
+
+  #[test]
+  fn limits_the_length() {
+    let longest = format!("/{}", "a".repeat(MAX_LEN - 1));
+    assert_eq!(parse(&longest), longest);
+    let too_long = format!("{}a", longest);
+    assert_eq!(
+      NspirePath::parse(&too_long),
+      Err(PathError::TooLong(too_long.clone()))
+    );
+  }
+
+  #[test]
+  fn deserializes_through_validation() {
+    let path: NspirePath = serde_json::from_str("\"/a//b/\"").unwrap();
+    assert_eq!(path.to_string(), "/a/b");
+    assert!(serde_json::from_str::<NspirePath>("\"/..\"").is_err());
+    assert_eq!(serde_json::to_string(&path).unwrap(), "\"/a/b\"");
+  }
+}
@@ -0,0 +1,425 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use libnspire::info::{Battery, Info, RunLevel, Version};
+use serde::Serialize;
+
+/// How far into the file to look for the header.
+const HEADER_SEARCH_LEN: usize = 4096;
+const HEADER_PREFIX: &[u8] = b"TI-Nspire.";
+
+/// A version as `(major, minor, patch, build)`, which can be compared.
+pub type VersionKey = (u8, u8, u8, u16);
+
+/// The oldest boot2 each OS series is known to install on, as
+/// `(model, OS major.minor, boot2)`. Later entries override earlier ones.
+const MIN_BOOT2: &[(Model, (u8, u8), VersionKey)] = &[
+  (Model::Cx, (3, 0), (3, 0, 0, 0)),
+  (Model::Cx, (4, 0), (4, 0, 0, 0)),
+  (Model::CxII, (5, 0), (5, 0, 0, 0)),
+  (Model::CxII, (5, 2), (5, 2, 0, 0)),
+];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+pub enum Model {
+  /// The original TI-Nspire and the TI-Nspire Touchpad.
+  Classic,
+  Cx,
+  CxII,
+}
+
+impl Model {
+  pub fn of(info: &Info, is_cx_ii: bool) -> Self {
+    if is_cx_ii {
+      Model::CxII
+    } else if info.hw_type.is_cx() {
+      Model::Cx
+    } else {
+      Model::Classic
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Model::Classic => "TI-Nspire",
+      Model::Cx => "TI-Nspire CX",
+      Model::CxII => "TI-Nspire CX II",
+    }
+  }
+}
+
+/// An OS upgrade file.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OsImage {
+  /// The file extension, without the leading dot.
+  pub extension: String,
+  pub model: Model,
+  pub cas: bool,
+  /// Whether the image is for the CX II-T, which has its own OS.
+  pub teacher: bool,
+  /// The version from the header, if one could be found.
+  pub version: Option<Version>,
+  pub size: usize,
+}
+
+/// Parses `4.5.0.1180` the same way the calculator reports its version.
+pub fn parse_version(s: &str) -> Option<Version> {
+  let mut parts = s.split('.');
+  let version = Version {
+    major: parts.next()?.parse().ok()?,
+    minor: parts.next()?.parse().ok()?,
+    patch: parts.next()?.parse().ok()?,
+    build: parts.next()?.parse().ok()?,
+  };
+  if parts.next().is_some() {
+    return None;
+  }
+  Some(version)
+}
+
+/// Orders versions, which `libnspire` doesn't do itself.
+pub fn version_key(version: &Version) -> VersionKey {
+  (version.major, version.minor, version.patch, version.build)
+}
+
+fn format_key((major, minor, patch, build): VersionKey) -> String {
+  format!("{}.{}.{}.{}", major, minor, patch, build)
+}
+
+/// Finds the `TI-Nspire.<ext> <version>` text at the start of an image.
+fn header_version(data: &[u8]) -> Option<Version> {
+  let data = &data[..data.len().min(HEADER_SEARCH_LEN)];
+  let start = data
+    .windows(HEADER_PREFIX.len())
+    .position(|window| window == HEADER_PREFIX)?
+    + HEADER_PREFIX.len();
+  let rest = &data[start..];
+  let ext_len = rest.iter().position(|b| !b.is_ascii_alphanumeric())?;
+  let rest = &rest[ext_len..];
+  let version_start = rest.iter().position(|b| !b.is_ascii_whitespace())?;
+  let rest = &rest[version_start..];
+  let version_len = rest
+    .iter()
+    .position(|b| !(b.is_ascii_digit() || *b == b'.'))
+    .unwrap_or(rest.len());
+  parse_version(std::str::from_utf8(&rest[..version_len]).ok()?)
+}
+
+impl OsImage {
+  pub fn parse(file_name: &str, data: &[u8]) -> anyhow::Result<Self> {
+    let extension = match file_name.rfind('.') {
+      Some(idx) => file_name[idx + 1..].to_lowercase(),
+      None => String::new(),
+    };
+    let (model, cas, teacher) = match extension.as_str() {
+      "tno" => (Model::Classic, false, false),
+      "tnc" => (Model::Classic, true, false),
+      "tco" => (Model::Cx, false, false),
+      "tcc" => (Model::Cx, true, false),
+      "tco2" => (Model::CxII, false, false),
+      "tcc2" => (Model::CxII, true, false),
+      "tct2" => (Model::CxII, false, true),
+      _ => anyhow::bail!(
+        "{} isn't an OS image: expected a .tno, .tnc, .tco, .tcc, .tco2, .tcc2 or .tct2 file",
+        file_name
+      ),
+    };
+    Ok(OsImage {
+      extension,
+      model,
+      cas,
+      teacher,
+      version: header_version(data),
+      size: data.len(),
+    })
+  }
+
+  /// Like [`OsImage::parse`], but only reads the start of the file.
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    let mut header = vec![];
+    File::open(path)?
+      .take(HEADER_SEARCH_LEN as u64)
+      .read_to_end(&mut header)?;
+    let name = path
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string())
+      .unwrap_or_default();
+    let mut image = OsImage::parse(&name, &header)?;
+    image.size = fs::metadata(path)?.len() as usize;
+    Ok(image)
+  }
+
+  pub fn model_name(&self) -> String {
+    let model = match self.model {
+      Model::CxII if self.teacher => "TI-Nspire CX II-T",
+      model => model.name(),
+    };
+    if self.cas {
+      format!("{} CAS", model)
+    } else {
+      model.to_string()
+    }
+  }
+
+  /// Reasons the image can't be installed on the calculator. Empty if it
+  /// matches.
+  pub fn check(&self, info: &Info, is_cx_ii: bool) -> Vec<String> {
+    let mut problems = vec![];
+    let model = Model::of(info, is_cx_ii);
+    if model != self.model {
+      problems.push(format!(
+        "This OS is for the {}, but {} is a {}",
+        self.model_name(),
+        info.name,
+        model.name()
+      ));
+    } else if self.cas != info.hw_type.is_cas() {
+      problems.push(format!(
+        "This OS is for the {}, but {} is {}a CAS model",
+        self.model_name(),
+        info.name,
+        if info.hw_type.is_cas() { "" } else { "not " }
+      ));
+    }
+    let expected = info.os_extension.trim_start_matches('.');
+    if !expected.is_empty() && !expected.eq_ignore_ascii_case(&self.extension) {
+      problems.push(format!(
+        "{} expects a .{} file, not .{}",
+        info.name, expected, self.extension
+      ));
+    }
+    problems
+  }
+
+  /// The oldest boot2 this image needs, if it is known.
+  pub fn min_boot2(&self) -> Option<VersionKey> {
+    let version = self.version.as_ref()?;
+    MIN_BOOT2
+      .iter()
+      .filter(|(model, os, _)| *model == self.model && (version.major, version.minor) >= *os)
+      .map(|(_, _, boot2)| *boot2)
+      .max()
+  }
+
+  /// Reasons installing the image right now could leave the calculator
+  /// unusable: a low battery, a downgrade, or a boot2 that is too old.
+  pub fn preflight(&self, info: &Info) -> Vec<String> {
+    let mut problems = vec![];
+    if info.battery == Battery::Low && !info.is_charging {
+      problems.push(format!(
+        "The battery of {} is low. Charge it or plug it in first",
+        info.name
+      ));
+    }
+    // In recovery the installed version is whatever the broken OS left.
+    if let (Some(version), RunLevel::Os) = (&self.version, info.run_level) {
+      if version_key(version) < version_key(&info.version) {
+        problems.push(format!(
+          "OS {} is older than the installed OS {}",
+          version, info.version
+        ));
+      }
+    }
+    if let Some(boot2) = self.min_boot2() {
+      if version_key(&info.boot2_version) < boot2 {
+        problems.push(format!(
+          "This OS needs boot2 {} or later, but {} has boot2 {}",
+          format_key(boot2),
+          info.name,
+          info.boot2_version
+        ));
+      }
+    }
+    problems
+  }
+}
+
+/// The OS images in `dir` that fit the calculator, newest first.
+pub fn find_images(
+  dir: &Path,
+  info: &Info,
+  is_cx_ii: bool,
+) -> anyhow::Result<Vec<(PathBuf, OsImage)>> {
+  let mut images = vec![];
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if !path.is_file() {
+      continue;
+    }
+    if let Ok(image) = OsImage::open(&path) {
+      if image.check(info, is_cx_ii).is_empty() {
+        images.push((path, image));
+      }
+    }
+  }
+  images.sort_by_key(|(_, image)| std::cmp::Reverse(image.version.as_ref().map(version_key)));
+  Ok(images)
+}
+
+impl std::fmt::Display for OsImage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.version {
+      Some(version) => write!(f, "{} OS {}", self.model_name(), version),
+      None => write!(f, "{} OS (unknown version)", self.model_name()),
+    }
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use libnspire::info::{HardwareType, Lcd};
+
+  use super::*;
+
+  fn version(s: &str) -> Version {
+    parse_version(s).unwrap()
+  }
+
+  /// A CX II CAS running 5.2 on boot2 5.2, with a full battery.
+  pub(crate) fn cx_ii_cas() -> Info {
+    Info {
+      free_storage: 0,
+      total_storage: 0,
+      free_ram: 0,
+      total_ram: 0,
+      version: version("5.2.0.771"),
+      boot1_version: version("5.0.0.0"),
+      boot2_version: version("5.2.0.0"),
+      hw_type: HardwareType::CasCx,
+      clock_speed: 0,
+      lcd: Lcd {
+        width: 320,
+        height: 240,
+        bpp: 16,
+        sample_mode: 0,
+      },
+      os_extension: ".tcc2".to_string(),
+      file_extension: ".tns".to_string(),
+      name: "Calc".to_string(),
+      id: "1234".to_string(),
+      run_level: RunLevel::Os,
+      battery: Battery::Ok,
+      is_charging: false,
+    }
+  }
+
+  fn image(file_name: &str, version: &str) -> OsImage {
+    let header = format!("\0\0TI-Nspire.{} {}\0rest", &file_name[4..], version);
+    OsImage::parse(file_name, header.as_bytes()).unwrap()
+  }
+
+  #[test]
+  fn parses_versions() {
+    assert_eq!(parse_version("4.5.0.1180"), Some(version("4.5.0.1180")));
+    assert_eq!(version_key(&version("4.5.3.14")), (4, 5, 3, 14));
+    assert_eq!(parse_version("4.5.0"), None);
+    assert_eq!(parse_version("4.5.0.1.2"), None);
+    assert_eq!(parse_version("4.x.0.1"), None);
+    assert_eq!(parse_version("4.5.0.70000"), None);
+  }
+
+  #[test]
+  fn reads_the_header_version() {
+    assert_eq!(
+      header_version(b"\x00\x01TI-Nspire.tcc2 5.3.0.564\x00"),
+      Some(version("5.3.0.564"))
+    );
+    assert_eq!(
+      header_version(b"TI-Nspire.tco   4.5.0.1180"),
+      Some(version("4.5.0.1180"))
+    );
+    assert_eq!(header_version(b"TI-Nspire.tco garbage"), None);
+    assert_eq!(header_version(b"no header here"), None);
+    let mut late = vec![0; HEADER_SEARCH_LEN];
+    late.extend_from_slice(b"TI-Nspire.tco 4.5.0.1180");
+    assert_eq!(header_version(&late), None);
+  }
+
+  #[test]
+  fn recognizes_every_extension() {
+    let cases = [
+      ("os.tno", Model::Classic, false, false),
+      ("os.tnc", Model::Classic, true, false),
+      ("os.tco", Model::Cx, false, false),
+      ("os.tcc", Model::Cx, true, false),
+      ("os.tco2", Model::CxII, false, false),
+      ("os.tcc2", Model::CxII, true, false),
+      ("OS.TCT2", Model::CxII, false, true),
+    ];
+    for (name, model, cas, teacher) in &cases {
+      let image = OsImage::parse(name, b"").unwrap();
+      assert_eq!(
+        (image.model, image.cas, image.teacher),
+        (*model, *cas, *teacher),
+        "{}",
+        name
+      );
+      assert_eq!(image.version, None);
+    }
+    assert!(OsImage::parse("notes.tns", b"").is_err());
+    assert!(OsImage::parse("os", b"").is_err());
+  }
+
+  #[test]
+  fn checks_the_model() {
+    let info = cx_ii_cas();
+    assert!(image("os.tcc2", "5.3.0.564").check(&info, true).is_empty());
+    assert_eq!(image("os.tco2", "5.3.0.564").check(&info, true).len(), 2);
+    assert_eq!(image("os.tcc", "4.5.0.1180").check(&info, true).len(), 2);
+    assert_eq!(image("os.tcc2", "5.3.0.564").check(&info, false).len(), 1);
+  }
+
+  #[test]
+  fn finds_the_minimum_boot2() {
+    assert_eq!(
+      image("os.tcc2", "5.3.0.564").min_boot2(),
+      Some((5, 2, 0, 0))
+    );
+    assert_eq!(image("os.tcc2", "5.0.0.1").min_boot2(), Some((5, 0, 0, 0)));
+    assert_eq!(
+      image("os.tcc", "4.5.0.1180").min_boot2(),
+      Some((4, 0, 0, 0))
+    );
+    assert_eq!(image("os.tnc", "3.9.0.1").min_boot2(), None);
+    assert_eq!(OsImage::parse("os.tcc2", b"").unwrap().min_boot2(), None);
+  }
+
+  #[test]
+  fn preflight_passes_an_upgrade() {
+    assert!(image("os.tcc2", "5.3.0.564")
+      .preflight(&cx_ii_cas())
+      .is_empty());
+  }
+
+  #[test]
+  fn preflight_refuses_a_downgrade_unless_in_recovery() {
+    let mut info = cx_ii_cas();
+    let older = image("os.tcc2", "5.2.0.700");
+    assert_eq!(older.preflight(&info).len(), 1);
+    info.run_level = RunLevel::Recovery;
+    assert!(older.preflight(&info).is_empty());
+  }
+
+  #[test]
+  fn preflight_refuses_a_low_battery_unless_charging() {
+    let mut info = cx_ii_cas();
+    info.battery = Battery::Low;
+    let upgrade = image("os.tcc2", "5.3.0.564");
+    assert_eq!(upgrade.preflight(&info).len(), 1);
+    info.is_charging = true;
+    assert!(upgrade.preflight(&info).is_empty());
+  }
+
+  #[test]
+  fn preflight_refuses_an_old_boot2() {
+    let mut info = cx_ii_cas();
+    info.boot2_version = version("5.0.0.1");
+    let problems = image("os.tcc2", "5.3.0.564").preflight(&info);
+    assert_eq!(
+      problems,
+      vec!["This OS needs boot2 5.2.0.0 or later, but Calc has boot2 5.0.0.1".to_string()]
+    );
+  }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use libnspire::info::Info;
+use libnspire::{PID, PID_CX2, VID};
+use rusb::GlobalContext;
+use serde::Serialize;
+
+use crate::os_image::{version_key, OsImage};
+
+/// How long the calculator may take to install the OS and come back.
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InstallPhase {
+  Sending,
+  /// The OS was sent and the calculator is writing it.
+  Installing,
+  /// The calculator left the bus to restart.
+  Rebooting,
+  /// The calculator came back running the new OS.
+  Verified,
+  Failed,
+}
+
+fn calculators() -> Vec<rusb::Device<GlobalContext>> {
+  rusb::devices()
+    .map(|list| {
+      list
+        .iter()
+        .filter(|dev| match dev.device_descriptor() {
+          Ok(d) => d.vendor_id() == VID && matches!(d.product_id(), PID | PID_CX2),
+          Err(_) => false,
+        })
+        .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Opens the calculators on the bus until one reports `id`. Ones that are
+/// busy or still starting up are skipped.
+fn find_by_id(id: &str) -> Option<Info> {
+  calculators().into_iter().find_map(|dev| {
+    let handle = libnspire::Handle::new(dev.open().ok()?).ok()?;
+    let info = handle.info().ok()?;
+    if info.id == id {
+      Some(info)
+    } else {
+      None
+    }
+  })
+}
+
+/// Waits for the calculator that was at `location` to restart after an OS
+/// install, calling `on_phase` once it has left the bus, and returns its info
+/// once it comes back. Matches it by `id`, since its address changes.
+pub fn wait_for_reboot(
+  id: &str,
+  location: (u8, u8),
+  mut on_phase: impl FnMut(InstallPhase),
+) -> anyhow::Result<Info> {
+  let start = Instant::now();
+  let mut left = false;
+  while start.elapsed() < REBOOT_TIMEOUT {
+    std::thread::sleep(POLL_INTERVAL);
+    if !left {
+      if calculators()
+        .iter()
+        .any(|dev| (dev.bus_number(), dev.address()) == location)
+      {
+        continue;
+      }
+      left = true;
+      on_phase(InstallPhase::Rebooting);
+    }
+    if let Some(info) = find_by_id(id) {
+      return Ok(info);
+    }
+  }
+  if left {
+    anyhow::bail!(
+      "The calculator didn't come back within {} minutes",
+      REBOOT_TIMEOUT.as_secs() / 60
+    )
+  } else {
+    anyhow::bail!("The calculator never restarted to install the OS")
+  }
+}
+
+/// Checks that the calculator now runs the version of `image`, if the image
+/// says which one it is.
+pub fn verify(image: &OsImage, info: &Info) -> anyhow::Result<()> {
+  match &image.version {
+    Some(version) if version_key(version) != version_key(&info.version) => anyhow::bail!(
+      "The calculator runs OS {} instead of {}",
+      info.version,
+      version
+    ),
+    _ => Ok(()),
+  }
+}
@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libnspire::info::Info;
+
+use crate::backup::sha256;
+use crate::os_image::{self, version_key, OsImage};
+
+/// A directory of OS images, sorted into one folder per file extension and
+/// named after their version: `tcc2/6.0.3.374.tcc2`.
+pub struct OsLibrary {
+  dir: PathBuf,
+}
+
+/// Where the library lives unless another directory is given.
+pub fn default_dir() -> anyhow::Result<PathBuf> {
+  let data = dirs_next::data_dir()
+    .ok_or_else(|| anyhow::anyhow!("Couldn't find a data directory for the OS library"))?;
+  Ok(data.join("n-link").join("os"))
+}
+
+impl OsLibrary {
+  pub fn open(dir: &Path) -> anyhow::Result<Self> {
+    fs::create_dir_all(dir)?;
+    Ok(OsLibrary {
+      dir: dir.to_path_buf(),
+    })
+  }
+
+  /// Copies an image into the library. Returns its new path and whether it
+  /// was already there.
+  pub fn add(&self, path: &Path) -> anyhow::Result<(PathBuf, OsImage, bool)> {
+    let image = OsImage::open(path)?;
+    let name = match &image.version {
+      Some(version) => version.to_string(),
+      // Without a version, only the contents tell images apart.
+      None => format!("unknown-{}", &sha256(&fs::read(path)?)[..12]),
+    };
+    let folder = self.dir.join(&image.extension);
+    let dest = folder.join(format!("{}.{}", name, image.extension));
+    if dest.exists() {
+      return Ok((dest, image, true));
+    }
+    fs::create_dir_all(&folder)?;
+    let part = folder.join(format!("{}.part", name));
+    fs::copy(path, &part)?;
+    fs::rename(&part, &dest)?;
+    Ok((dest, image, false))
+  }
+
+  /// Every image in the library, grouped by model with the newest first.
+  pub fn list(&self) -> anyhow::Result<Vec<(PathBuf, OsImage)>> {
+    let mut images = vec![];
+    for folder in fs::read_dir(&self.dir)? {
+      let folder = folder?.path();
+      if !folder.is_dir() {
+        continue;
+      }
+      for entry in fs::read_dir(&folder)? {
+        let path = entry?.path();
+        if let Ok(image) = OsImage::open(&path) {
+          images.push((path, image));
+        }
+      }
+    }
+    images.sort_by(|(_, a), (_, b)| {
+      a.model_name().cmp(&b.model_name()).then_with(|| {
+        b.version
+          .as_ref()
+          .map(version_key)
+          .cmp(&a.version.as_ref().map(version_key))
+      })
+    });
+    Ok(images)
+  }
+
+  /// The newest image that fits the calculator.
+  pub fn latest(&self, info: &Info, is_cx_ii: bool) -> anyhow::Result<Option<(PathBuf, OsImage)>> {
+    let folder = self.dir.join(info.os_extension.trim_start_matches('.'));
+    if !folder.is_dir() {
+      return Ok(None);
+    }
+    Ok(
+      os_image::find_images(&folder, info, is_cx_ii)?
+        .into_iter()
+        .next(),
+    )
+  }
+
+  /// The image of a given version that fits the calculator.
+  pub fn find(
+    &self,
+    info: &Info,
+    is_cx_ii: bool,
+    version: &str,
+  ) -> anyhow::Result<Option<(PathBuf, OsImage)>> {
+    let folder = self.dir.join(info.os_extension.trim_start_matches('.'));
+    if !folder.is_dir() {
+      return Ok(None);
+    }
+    Ok(
+      os_image::find_images(&folder, info, is_cx_ii)?
+        .into_iter()
+        .find(|(_, image)| matches!(&image.version, Some(v) if v.to_string() == version)),
+    )
+  }
+}
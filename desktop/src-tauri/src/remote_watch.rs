@@ -0,0 +1,161 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hashbrown::HashMap;
+use rusb::GlobalContext;
+use tauri::{Runtime, Window};
+
+use crate::cmd::{DevId, RemoteChange};
+use crate::sanitize;
+use crate::tree::{changes, join, walk_calc, ChangeKind, TreeEntry};
+use crate::{err_wrap, get_open_dev};
+
+lazy_static::lazy_static! {
+  /// Stop flags for the running remote watches, one per device.
+  static ref WATCHES: Mutex<HashMap<(u8, u8), Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+fn download(
+  handle: &libnspire::Handle<GlobalContext>,
+  remote: &str,
+  entry: &TreeEntry,
+  dest: &Path,
+) -> anyhow::Result<()> {
+  let path = dest.join(sanitize::relative_path(&entry.path)?);
+  if entry.is_dir {
+    fs::create_dir_all(path)?;
+    return Ok(());
+  }
+  let mut buf = vec![0; entry.size as usize];
+  let len = handle.read_file(&join(remote, &entry.path), &mut buf, &mut |_| {})?;
+  buf.truncate(len);
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent)?;
+  }
+  File::create(path)?.write_all(&buf)?;
+  Ok(())
+}
+
+/// Whether a local copy of `entry` is already present in `dest`.
+fn is_mirrored(entry: &TreeEntry, dest: &Path) -> bool {
+  let path = match sanitize::relative_path(&entry.path) {
+    Ok(path) => dest.join(path),
+    Err(_) => return false,
+  };
+  match fs::metadata(path) {
+    Ok(meta) => meta.is_dir() == entry.is_dir && (entry.is_dir || meta.len() == entry.size),
+    Err(_) => false,
+  }
+}
+
+fn poll<R: Runtime>(
+  dev: DevId,
+  path: &str,
+  dest: Option<&Path>,
+  previous: &mut Option<Vec<TreeEntry>>,
+  window: &Window<R>,
+) -> anyhow::Result<()> {
+  // The device is only locked while listing and for each download, so other
+  // commands can use it in between.
+  let device = get_open_dev(&dev)?;
+  let snapshot = {
+    let handle = device.lock().unwrap();
+    err_wrap(walk_calc(&handle, path), dev, window)?
+  };
+  let found = match previous {
+    Some(previous) => changes(previous, &snapshot),
+    // On the first pass only fetch what is missing locally.
+    None => match dest {
+      Some(dest) => snapshot
+        .iter()
+        .filter(|entry| !is_mirrored(entry, dest))
+        .map(|entry| (ChangeKind::Created, entry.clone()))
+        .collect(),
+      None => vec![],
+    },
+  };
+  for (kind, entry) in found {
+    let mut error = None;
+    if let (Some(dest), ChangeKind::Created) | (Some(dest), ChangeKind::Modified) = (dest, kind) {
+      let res = download(&device.lock().unwrap(), path, &entry, dest);
+      if let Err(e) = res {
+        error = Some(e.to_string());
+      }
+    }
+    if let Err(msg) = window.emit(
+      "remoteChange",
+      RemoteChange {
+        dev,
+        kind,
+        entry,
+        error,
+      },
+    ) {
+      eprintln!("{}", msg);
+    }
+  }
+  *previous = Some(snapshot);
+  Ok(())
+}
+
+/// Starts polling `path` on the device every `interval`, emitting a
+/// `remoteChange` event for every file or folder created, modified or deleted
+/// and mirroring new or changed files into `dest` if given. Replaces any watch
+/// already running on the device.
+pub fn start<R: Runtime>(
+  dev: DevId,
+  path: String,
+  interval: Duration,
+  dest: Option<PathBuf>,
+  window: Window<R>,
+) {
+  let stop = Arc::new(AtomicBool::new(false));
+  if let Some(old) = WATCHES
+    .lock()
+    .unwrap()
+    .insert((dev.bus_number, dev.address), stop.clone())
+  {
+    old.store(true, Ordering::SeqCst);
+  }
+  std::thread::spawn(move || {
+    let mut previous = None;
+    while !stop.load(Ordering::SeqCst) {
+      let started = Instant::now();
+      if let Err(error) = poll(dev, &path, dest.as_deref(), &mut previous, &window) {
+        eprintln!("Stopped watching {}: {}", path, error);
+        if let Err(msg) = window.emit("remoteWatchStopped", dev) {
+          eprintln!("{}", msg);
+        }
+        break;
+      }
+      while !stop.load(Ordering::SeqCst) && started.elapsed() < interval {
+        std::thread::sleep(Duration::from_millis(100));
+      }
+    }
+    let mut watches = WATCHES.lock().unwrap();
+    if let Some(current) = watches.get(&(dev.bus_number, dev.address)) {
+      if Arc::ptr_eq(current, &stop) {
+        watches.remove(&(dev.bus_number, dev.address));
+      }
+    }
+  });
+}
+
+/// Stops the watch running on the device, if any.
+pub fn stop(dev: DevId) -> bool {
+  match WATCHES
+    .lock()
+    .unwrap()
+    .remove(&(dev.bus_number, dev.address))
+  {
+    Some(stop) => {
+      stop.store(true, Ordering::SeqCst);
+      true
+    }
+    None => false,
+  }
+}
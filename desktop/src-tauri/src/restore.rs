@@ -0,0 +1,305 @@
+use std::collections::{HashMap, HashSet};
+
+use libnspire::info::Info;
+use rusb::GlobalContext;
+use serde::Serialize;
+
+use crate::backup::BackupArchive;
+use crate::conflict::{renamed, ConflictPolicy};
+use crate::nspire_path::NspirePath;
+use crate::space;
+use crate::tree::walk_calc;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RestoreAction {
+  Upload,
+  Overwrite,
+  Rename,
+  Skip,
+  /// The archive path can't be written to the calculator.
+  Invalid,
+}
+
+impl RestoreAction {
+  /// Whether the file gets written to the calculator.
+  pub fn writes(self) -> bool {
+    !matches!(self, RestoreAction::Skip | RestoreAction::Invalid)
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreItem {
+  /// Path relative to the backup root.
+  pub path: String,
+  /// Full calculator path the file will be written to, or empty if it's
+  /// invalid.
+  pub dest: String,
+  pub action: RestoreAction,
+  pub size: u64,
+  /// Why the file can't be restored, for invalid ones.
+  pub reason: Option<String>,
+  /// Index of the file in the archive manifest.
+  #[serde(skip)]
+  pub entry: usize,
+}
+
+pub struct RestorePlan {
+  pub root: NspirePath,
+  /// Directories to create.
+  pub dirs: Vec<NspirePath>,
+  pub items: Vec<RestoreItem>,
+  /// Additional storage the restore will use up.
+  pub needed: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoreResult {
+  #[serde(flatten)]
+  pub item: RestoreItem,
+  pub error: Option<String>,
+}
+
+/// Resolves a path from an archive manifest under `root`, returning the full
+/// path and the normalized path relative to `root`. Manifests can't be
+/// trusted, so paths that are invalid on the calculator or end up outside
+/// `root` are refused.
+fn resolve(root: &NspirePath, path: &str) -> Result<(NspirePath, String), String> {
+  let dest = root
+    .join(path.trim_start_matches('/'))
+    .map_err(|e| e.to_string())?;
+  let rel = if root.is_root() {
+    Some(&dest[1..])
+  } else if dest.starts_with(&format!("{}/", root)) {
+    Some(&dest[root.len() + 1..])
+  } else {
+    None
+  };
+  match rel {
+    Some(rel) if !rel.is_empty() => {
+      let rel = rel.to_string();
+      Ok((dest, rel))
+    }
+    _ => Err(format!("{} points outside the restore folder", path)),
+  }
+}
+
+/// Decides where each file in the archive goes and how much space is needed.
+pub fn plan(
+  handle: &libnspire::Handle<GlobalContext>,
+  archive: &BackupArchive,
+  root: &NspirePath,
+  policy: ConflictPolicy,
+) -> anyhow::Result<RestorePlan> {
+  if policy == ConflictPolicy::Ask {
+    anyhow::bail!("Restores can't ask about each file, choose another conflict policy");
+  }
+  let existing = match walk_calc(handle, root) {
+    Ok(entries) => entries,
+    Err(libnspire::Error::DoesNotExist) => vec![],
+    Err(e) => return Err(e.into()),
+  };
+  let mut taken: HashSet<String> = existing.iter().map(|entry| entry.path.clone()).collect();
+  let existing_dirs: HashSet<_> = existing
+    .iter()
+    .filter(|entry| entry.is_dir)
+    .map(|entry| entry.path.clone())
+    .collect();
+  let existing_files: HashMap<_, _> = existing
+    .iter()
+    .filter(|entry| !entry.is_dir)
+    .map(|entry| (entry.path.as_str(), entry))
+    .collect();
+  let mut freed = 0;
+  let mut plan = RestorePlan {
+    root: root.clone(),
+    dirs: vec![],
+    items: vec![],
+    needed: 0,
+  };
+  for (index, entry) in archive.manifest.files.iter().enumerate() {
+    let resolved = resolve(root, &entry.path);
+    if entry.is_dir {
+      // Files inside an invalid directory are invalid too, and get reported
+      // on their own
+      if let Ok((dest, rel)) = resolved {
+        if !existing_dirs.contains(&rel) {
+          plan.dirs.push(dest);
+        }
+      }
+      continue;
+    }
+    let (dest, rel) = match resolved {
+      Ok(resolved) => resolved,
+      Err(reason) => {
+        plan.items.push(RestoreItem {
+          path: entry.path.clone(),
+          dest: String::new(),
+          action: RestoreAction::Invalid,
+          size: entry.size,
+          reason: Some(reason),
+          entry: index,
+        });
+        continue;
+      }
+    };
+    let (action, dest) = match (existing_files.get(rel.as_str()), policy) {
+      (None, _) => (RestoreAction::Upload, dest),
+      (Some(_), ConflictPolicy::Skip) | (Some(_), ConflictPolicy::Ask) => {
+        (RestoreAction::Skip, dest)
+      }
+      (Some(old), ConflictPolicy::NewerOnly) if entry.date <= old.date => {
+        (RestoreAction::Skip, dest)
+      }
+      (Some(old), ConflictPolicy::Overwrite) | (Some(old), ConflictPolicy::NewerOnly) => {
+        freed += old.size;
+        (RestoreAction::Overwrite, dest)
+      }
+      (Some(_), ConflictPolicy::Rename) => {
+        let rel = (1..)
+          .map(|n| renamed(&rel, n))
+          .find(|path| !taken.contains(path))
+          .unwrap();
+        taken.insert(rel.clone());
+        match root.join(&rel) {
+          Ok(dest) => (RestoreAction::Rename, dest),
+          Err(error) => {
+            plan.items.push(RestoreItem {
+              path: entry.path.clone(),
+              dest: String::new(),
+              action: RestoreAction::Invalid,
+              size: entry.size,
+              reason: Some(error.to_string()),
+              entry: index,
+            });
+            continue;
+          }
+        }
+      }
+    };
+    if action.writes() {
+      plan.needed += entry.size;
+    }
+    taken.insert(rel);
+    plan.items.push(RestoreItem {
+      path: entry.path.clone(),
+      dest: dest.to_string(),
+      action,
+      size: entry.size,
+      reason: None,
+      entry: index,
+    });
+  }
+  plan.needed = plan.needed.saturating_sub(freed);
+  Ok(plan)
+}
+
+/// Fails if the calculator doesn't have room for the restore.
+pub fn check_space(info: &Info, plan: &RestorePlan) -> anyhow::Result<()> {
+  space::check(info, plan.needed)?;
+  Ok(())
+}
+
+/// Creates the planned directories and uploads every file. `progress` is
+/// called with the file being written and the number of bytes left in the
+/// whole restore. Stops early only if the calculator is disconnected.
+pub fn restore(
+  handle: &libnspire::Handle<GlobalContext>,
+  archive: &BackupArchive,
+  plan: &RestorePlan,
+  progress: &mut dyn FnMut(&str, usize),
+) -> anyhow::Result<Vec<RestoreResult>> {
+  let mut root = String::new();
+  for part in plan.root.split('/').filter(|part| !part.is_empty()) {
+    root = format!("{}/{}", root, part);
+    match handle.create_dir(&root) {
+      Ok(_) | Err(libnspire::Error::Exists) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  for dir in &plan.dirs {
+    match handle.create_dir(dir) {
+      Ok(_) | Err(libnspire::Error::Exists) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
+  let mut remaining: usize = plan
+    .items
+    .iter()
+    .filter(|item| item.action.writes())
+    .map(|item| item.size as usize)
+    .sum();
+  let mut results = vec![];
+  for item in &plan.items {
+    if !item.action.writes() {
+      results.push(RestoreResult {
+        item: item.clone(),
+        error: item.reason.clone(),
+      });
+      continue;
+    }
+    let entry = &archive.manifest.files[item.entry];
+    let before = remaining;
+    let res = archive.contents(entry).and_then(|data| {
+      handle
+        .write_file(&item.dest, data, &mut |left| {
+          progress(&item.path, before - data.len().saturating_sub(left))
+        })
+        .map_err(anyhow::Error::from)
+    });
+    remaining -= item.size as usize;
+    if let Err(error) = &res {
+      if let Some(libnspire::Error::NoDevice) = error.downcast_ref() {
+        return Err(libnspire::Error::NoDevice.into());
+      }
+    }
+    results.push(RestoreResult {
+      item: item.clone(),
+      error: res.err().map(|e| e.to_string()),
+    });
+  }
+  Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_manifest_paths_under_the_root() {
+    let root = NspirePath::parse("/restore").unwrap();
+    let resolve = |path: &str| resolve(&root, path).map(|(dest, rel)| (dest.to_string(), rel));
+    assert_eq!(
+      resolve("class/quiz.tns"),
+      Ok((
+        "/restore/class/quiz.tns".to_string(),
+        "class/quiz.tns".to_string()
+      ))
+    );
+    assert_eq!(
+      resolve("/class//quiz.tns"),
+      Ok((
+        "/restore/class/quiz.tns".to_string(),
+        "class/quiz.tns".to_string()
+      ))
+    );
+    assert!(resolve("../quiz.tns").is_err());
+    assert!(resolve("class/../../restored/quiz.tns").is_err());
+    assert!(resolve("..").is_err());
+    assert!(resolve("").is_err());
+    assert!(resolve("quiz?.tns").is_err());
+    assert!(resolve(&"a".repeat(300)).is_err());
+  }
+
+  #[test]
+  fn resolves_everything_below_the_calculator_root() {
+    let root = NspirePath::root();
+    assert_eq!(
+      resolve(&root, "quiz.tns").map(|(dest, rel)| (dest.to_string(), rel)),
+      Ok(("/quiz.tns".to_string(), "quiz.tns".to_string()))
+    );
+    assert!(resolve(&root, "../quiz.tns").is_err());
+  }
+}
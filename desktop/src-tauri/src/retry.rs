@@ -0,0 +1,215 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+lazy_static::lazy_static! {
+  /// The policy used for every retried operation.
+  static ref POLICY: RwLock<RetryPolicy> = RwLock::new(RetryPolicy::default());
+}
+
+/// How often, and how patiently, to retry operations that failed with an
+/// error that may go away on its own.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+  /// Attempts in total, including the first one. 1 disables retrying.
+  pub max_attempts: u32,
+  /// Delay before the first retry, doubled after each one.
+  pub initial_delay_ms: u64,
+  pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 5,
+      initial_delay_ms: 250,
+      max_delay_ms: 4000,
+    }
+  }
+}
+
+/// Errors that are worth retrying: timeouts, stalls and a busy device.
+/// libnspire reports stalls, overflows and interrupted transfers as
+/// [`libnspire::Error::LibUsb`].
+pub trait Transient {
+  fn is_transient(&self) -> bool;
+}
+
+impl Transient for rusb::Error {
+  fn is_transient(&self) -> bool {
+    matches!(
+      self,
+      rusb::Error::Timeout | rusb::Error::Pipe | rusb::Error::Busy
+    )
+  }
+}
+
+impl Transient for libnspire::Error {
+  fn is_transient(&self) -> bool {
+    match self {
+      libnspire::Error::Timeout | libnspire::Error::Busy | libnspire::Error::LibUsb => true,
+      libnspire::Error::Usb(e) => e.is_transient(),
+      _ => false,
+    }
+  }
+}
+
+pub fn policy() -> RetryPolicy {
+  *POLICY.read().unwrap()
+}
+
+pub fn set_policy(policy: RetryPolicy) {
+  *POLICY.write().unwrap() = policy;
+}
+
+impl RetryPolicy {
+  /// The delay before retry number `retry`, starting at 1.
+  pub fn delay(&self, retry: u32) -> Duration {
+    let factor = 1u64
+      .checked_shl(retry.saturating_sub(1))
+      .unwrap_or(u64::MAX);
+    Duration::from_millis(
+      self
+        .initial_delay_ms
+        .saturating_mul(factor)
+        .min(self.max_delay_ms),
+    )
+  }
+
+  /// Runs `op` until it succeeds, fails with an error that isn't
+  /// [`Transient`], or runs out of attempts. `on_retry` is called before each
+  /// retry with the attempt about to be made, the error and the delay.
+  pub fn run<T, E: Transient>(
+    &self,
+    mut op: impl FnMut() -> Result<T, E>,
+    mut on_retry: impl FnMut(u32, &E, Duration),
+  ) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+      match op() {
+        Err(e) if e.is_transient() && attempt < self.max_attempts => {
+          let delay = self.delay(attempt);
+          attempt += 1;
+          on_retry(attempt, &e, delay);
+          std::thread::sleep(delay);
+        }
+        res => return res,
+      }
+    }
+  }
+}
+
+/// Runs `op` with the current policy, printing each retry to stderr.
+pub fn with_retry<T, E: Transient + std::fmt::Display>(
+  what: &str,
+  op: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+  let policy = policy();
+  policy.run(op, |attempt, error, delay| {
+    eprintln!(
+      "{} failed: {}. Retrying in {} ms (attempt {}/{})",
+      what,
+      error,
+      delay.as_millis(),
+      attempt,
+      policy.max_attempts
+    )
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn classifies_libnspire_errors() {
+    let transient = vec![
+      libnspire::Error::Timeout,
+      libnspire::Error::Busy,
+      libnspire::Error::LibUsb,
+      libnspire::Error::Usb(rusb::Error::Timeout),
+      libnspire::Error::Usb(rusb::Error::Pipe),
+      libnspire::Error::Usb(rusb::Error::Busy),
+    ];
+    for error in &transient {
+      assert!(error.is_transient(), "{:?}", error);
+    }
+    let permanent = vec![
+      libnspire::Error::Io,
+      libnspire::Error::Access,
+      libnspire::Error::NotSupported,
+      libnspire::Error::OutOfMemory,
+      libnspire::Error::NoDevice,
+      libnspire::Error::InvalidPacket,
+      libnspire::Error::Nack,
+      libnspire::Error::Invalid,
+      libnspire::Error::Exists,
+      libnspire::Error::DoesNotExist,
+      libnspire::Error::UnknownBpp(3),
+      libnspire::Error::Unknown,
+      libnspire::Error::Usb(rusb::Error::NoDevice),
+      libnspire::Error::Usb(rusb::Error::Access),
+    ];
+    for error in &permanent {
+      assert!(!error.is_transient(), "{:?}", error);
+    }
+  }
+
+  #[test]
+  fn doubles_the_delay_up_to_the_cap() {
+    let policy = RetryPolicy::default();
+    let delays: Vec<u128> = (1..=7)
+      .map(|retry| policy.delay(retry).as_millis())
+      .collect();
+    assert_eq!(delays, vec![250, 500, 1000, 2000, 4000, 4000, 4000]);
+    assert_eq!(policy.delay(200).as_millis(), 4000);
+    assert_eq!(policy.delay(u32::MAX).as_millis(), 4000);
+  }
+
+  #[test]
+  fn retries_only_transient_errors_up_to_the_limit() {
+    let policy = RetryPolicy {
+      max_attempts: 3,
+      initial_delay_ms: 0,
+      max_delay_ms: 0,
+    };
+    let mut calls = 0;
+    let res: Result<(), _> = policy.run(
+      || {
+        calls += 1;
+        Err(libnspire::Error::LibUsb)
+      },
+      |_, _, _| {},
+    );
+    assert!(res.is_err());
+    assert_eq!(calls, 3);
+
+    let mut calls = 0;
+    let res: Result<(), _> = policy.run(
+      || {
+        calls += 1;
+        Err(libnspire::Error::DoesNotExist)
+      },
+      |_, _, _| {},
+    );
+    assert!(res.is_err());
+    assert_eq!(calls, 1);
+
+    let mut calls = 0;
+    let mut attempts = vec![];
+    let res = policy.run(
+      || {
+        calls += 1;
+        match calls {
+          1 => Err(libnspire::Error::Timeout),
+          n => Ok(n),
+        }
+      },
+      |attempt, _, _| attempts.push(attempt),
+    );
+    assert_eq!(res.unwrap(), 2);
+    assert_eq!(attempts, vec![2]);
+  }
+}
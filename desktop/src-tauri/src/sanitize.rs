@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+
+/// Characters that can't appear in file names on at least one desktop OS.
+const RESERVED_CHARS: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+  "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+  "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NameError {
+  Empty,
+  /// `.` or `..`, which would escape the destination folder.
+  Traversal(String),
+}
+
+impl std::fmt::Display for NameError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      NameError::Empty => write!(f, "File name is empty"),
+      NameError::Traversal(name) => write!(f, "Refusing to write to {:?}", name),
+    }
+  }
+}
+
+impl std::error::Error for NameError {}
+
+/// Turns a single name from the calculator into one that is safe to create
+/// in a local folder on any OS. Reserved characters become `_`, device names
+/// such as `CON` get a `_` appended, and `.`/`..` are rejected.
+pub fn file_name(name: &str) -> Result<String, NameError> {
+  if name.is_empty() {
+    return Err(NameError::Empty);
+  }
+  if name == "." || name == ".." {
+    return Err(NameError::Traversal(name.to_string()));
+  }
+  let mut safe: String = name
+    .chars()
+    .map(|c| {
+      if c.is_control() || RESERVED_CHARS.contains(&c) {
+        '_'
+      } else {
+        c
+      }
+    })
+    .collect();
+  // Windows silently drops trailing dots and spaces.
+  let kept = safe.trim_end_matches(&['.', ' '][..]).len();
+  let trailing = "_".repeat(safe.len() - kept);
+  safe.truncate(kept);
+  safe.push_str(&trailing);
+
+  let stem_len = safe.find('.').unwrap_or(safe.len());
+  let stem = safe[..stem_len].trim_end_matches(' ');
+  if RESERVED_NAMES
+    .iter()
+    .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+  {
+    safe.insert(stem_len, '_');
+  }
+  Ok(safe)
+}
+
+/// The last component of a calculator path, made safe with [`file_name`].
+pub fn calc_file_name(path: &str) -> Result<String, NameError> {
+  file_name(path.trim_end_matches('/').rsplit('/').next().unwrap_or(""))
+}
+
+/// Converts a `/`-separated path relative to a calculator folder into a
+/// relative local path, checking every component with [`file_name`].
+pub fn relative_path(path: &str) -> Result<PathBuf, NameError> {
+  let mut local = PathBuf::new();
+  for part in path.split('/').filter(|part| !part.is_empty()) {
+    local.push(file_name(part)?);
+  }
+  if local.as_os_str().is_empty() {
+    return Err(NameError::Empty);
+  }
+  Ok(local)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_ordinary_names() {
+    assert_eq!(file_name("notes.tns").unwrap(), "notes.tns");
+    assert_eq!(file_name("Ünïcödé 1.tns").unwrap(), "Ünïcödé 1.tns");
+    assert_eq!(file_name(".hidden").unwrap(), ".hidden");
+  }
+
+  #[test]
+  fn rejects_traversal_and_empty_names() {
+    assert_eq!(file_name(""), Err(NameError::Empty));
+    assert_eq!(file_name("."), Err(NameError::Traversal(".".into())));
+    assert_eq!(file_name(".."), Err(NameError::Traversal("..".into())));
+    assert_eq!(
+      calc_file_name("/documents/.."),
+      Err(NameError::Traversal("..".into()))
+    );
+    assert_eq!(calc_file_name("/"), Err(NameError::Empty));
+    assert!(relative_path("a/../b").is_err());
+    assert_eq!(relative_path("//"), Err(NameError::Empty));
+  }
+
+  #[test]
+  fn maps_reserved_characters() {
+    assert_eq!(file_name("a/b").unwrap(), "a_b");
+    assert_eq!(file_name("a\\..\\b").unwrap(), "a_.._b");
+    assert_eq!(file_name("what?<>:*|\".tns").unwrap(), "what_______.tns");
+    assert_eq!(file_name("tab\there\n").unwrap(), "tab_here_");
+  }
+
+  #[test]
+  fn maps_trailing_dots_and_spaces() {
+    assert_eq!(file_name("name.").unwrap(), "name_");
+    assert_eq!(file_name("name .. ").unwrap(), "name____");
+    assert_eq!(file_name("...").unwrap(), "___");
+  }
+
+  #[test]
+  fn maps_device_names() {
+    assert_eq!(file_name("CON").unwrap(), "CON_");
+    assert_eq!(file_name("con.tns").unwrap(), "con_.tns");
+    assert_eq!(file_name("Lpt1.tar.gz").unwrap(), "Lpt1_.tar.gz");
+    assert_eq!(file_name("nul .txt").unwrap(), "nul _.txt");
+    assert_eq!(file_name("console.tns").unwrap(), "console.tns");
+    assert_eq!(file_name("COM10").unwrap(), "COM10");
+  }
+
+  #[test]
+  fn takes_the_last_component() {
+    assert_eq!(calc_file_name("/documents/test.tns").unwrap(), "test.tns");
+    assert_eq!(calc_file_name("/documents/folder/").unwrap(), "folder");
+    assert_eq!(calc_file_name("test.tns").unwrap(), "test.tns");
+  }
+
+  #[test]
+  fn converts_relative_paths() {
+    assert_eq!(
+      relative_path("folder/aux.tns").unwrap(),
+      PathBuf::from("folder").join("aux_.tns")
+    );
+    assert_eq!(
+      relative_path("/a//b/").unwrap(),
+      PathBuf::from("a").join("b")
+    );
+  }
+}
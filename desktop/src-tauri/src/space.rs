@@ -0,0 +1,64 @@
+use indicatif::HumanBytes;
+use libnspire::info::Info;
+use rusb::GlobalContext;
+
+/// The calculator doesn't have room for a transfer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InsufficientStorage {
+  pub needed: u64,
+  pub free: u64,
+}
+
+impl std::fmt::Display for InsufficientStorage {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(
+      f,
+      "Insufficient storage on the calculator: {} needed, {} free, {} short",
+      HumanBytes(self.needed),
+      HumanBytes(self.free),
+      HumanBytes(self.needed - self.free)
+    )
+  }
+}
+
+impl std::error::Error for InsufficientStorage {}
+
+/// Fails if `needed` bytes don't fit in the calculator's free storage.
+pub fn check(info: &Info, needed: u64) -> Result<(), InsufficientStorage> {
+  if needed > info.free_storage {
+    return Err(InsufficientStorage {
+      needed,
+      free: info.free_storage,
+    });
+  }
+  Ok(())
+}
+
+/// Bytes needed to write files of the given sizes to the given calculator
+/// paths. Files that replace existing ones only count by how much they grow.
+pub fn upload_needed<'a>(
+  handle: &libnspire::Handle<GlobalContext>,
+  files: impl IntoIterator<Item = (&'a str, u64)>,
+) -> libnspire::Result<u64> {
+  let mut needed = 0;
+  for (dest, size) in files {
+    needed += match handle.file_attr(dest) {
+      Ok(attr) => size.saturating_sub(attr.size()),
+      Err(libnspire::Error::DoesNotExist) => size,
+      Err(e) => return Err(e),
+    };
+  }
+  Ok(needed)
+}
+
+/// Checks that files of the given sizes fit on the calculator, using fresh
+/// info since the cached free storage may be out of date.
+pub fn check_upload<'a>(
+  handle: &libnspire::Handle<GlobalContext>,
+  files: impl IntoIterator<Item = (&'a str, u64)>,
+) -> anyhow::Result<Info> {
+  let needed = upload_needed(handle, files)?;
+  let info = handle.info()?;
+  check(&info, needed)?;
+  Ok(info)
+}
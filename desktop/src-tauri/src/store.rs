@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use libnspire::info::Info;
+use rusb::GlobalContext;
+use serde::Serialize;
+
+use crate::backup::{sha256, BackupArchive, Manifest, ManifestEntry, SkippedEntry};
+use crate::tree::{join, TreeEntry};
+
+/// Directory in the store holding file contents, named by their SHA-256.
+const OBJECTS_DIR: &str = "objects";
+/// Directory in the store holding one manifest per snapshot, grouped by
+/// calculator ID.
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotStats {
+  /// Files read from the calculator.
+  pub read: usize,
+  /// Files skipped because they were unchanged since the previous snapshot.
+  pub unchanged: usize,
+  /// Bytes of new contents added to the store.
+  pub stored: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PruneResult {
+  pub snapshots: Vec<Manifest>,
+  /// Number of objects no snapshot referred to any more.
+  pub objects: usize,
+  pub freed: u64,
+}
+
+/// A directory of deduplicated backups. Each snapshot is a manifest stored
+/// under `snapshots/<device id>/<created>.json`, and each distinct file is
+/// stored once under `objects/`.
+pub struct Store {
+  dir: PathBuf,
+}
+
+/// Keeps calculator IDs usable as directory names.
+fn device_dir(device_id: &str) -> String {
+  device_id
+    .chars()
+    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+    .collect()
+}
+
+impl Store {
+  pub fn open(dir: &Path) -> anyhow::Result<Self> {
+    fs::create_dir_all(dir.join(OBJECTS_DIR))?;
+    fs::create_dir_all(dir.join(SNAPSHOTS_DIR))?;
+    Ok(Store {
+      dir: dir.to_path_buf(),
+    })
+  }
+
+  fn object_path(&self, hash: &str) -> PathBuf {
+    self.dir.join(OBJECTS_DIR).join(&hash[..2]).join(&hash[2..])
+  }
+
+  fn snapshot_path(&self, device_id: &str, created: u64) -> PathBuf {
+    self
+      .dir
+      .join(SNAPSHOTS_DIR)
+      .join(device_dir(device_id))
+      .join(format!("{}.json", created))
+  }
+
+  fn has_object(&self, hash: &str) -> bool {
+    self.object_path(hash).is_file()
+  }
+
+  /// Adds `data` to the store, returning its hash and whether it was new.
+  fn put_object(&self, data: &[u8]) -> anyhow::Result<(String, bool)> {
+    let hash = sha256(data);
+    let path = self.object_path(&hash);
+    if path.is_file() {
+      return Ok((hash, false));
+    }
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    // Write to a temporary name first so an interrupted backup never leaves a
+    // truncated object behind.
+    let tmp = path.with_extension("tmp");
+    File::create(&tmp)?.write_all(data)?;
+    fs::rename(&tmp, &path)?;
+    Ok((hash, true))
+  }
+
+  fn get_object(&self, hash: &str) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![];
+    File::open(self.object_path(hash))
+      .map_err(|e| anyhow::anyhow!("Object {} is missing from the store: {}", hash, e))?
+      .read_to_end(&mut data)?;
+    Ok(data)
+  }
+
+  /// All snapshots, optionally only those of one calculator, oldest first.
+  pub fn snapshots(&self, device_id: Option<&str>) -> anyhow::Result<Vec<Manifest>> {
+    let mut snapshots = vec![];
+    for dir in fs::read_dir(self.dir.join(SNAPSHOTS_DIR))? {
+      let dir = dir?;
+      if !dir.file_type()?.is_dir() {
+        continue;
+      }
+      if let Some(id) = device_id {
+        if dir.file_name().to_string_lossy() != device_dir(id) {
+          continue;
+        }
+      }
+      for file in fs::read_dir(dir.path())? {
+        let path = file?.path();
+        if path.extension() == Some(OsStr::new("json")) {
+          snapshots.push(serde_json::from_reader::<_, Manifest>(File::open(&path)?)?);
+        }
+      }
+    }
+    snapshots.sort_by(|a, b| {
+      a.device_id
+        .cmp(&b.device_id)
+        .then(a.created.cmp(&b.created))
+    });
+    Ok(snapshots)
+  }
+
+  pub fn snapshot(&self, device_id: &str, created: u64) -> anyhow::Result<Manifest> {
+    let path = self.snapshot_path(device_id, created);
+    let file = File::open(&path)
+      .map_err(|_| anyhow::anyhow!("No snapshot of {} taken at {}", device_id, created))?;
+    Ok(serde_json::from_reader(file)?)
+  }
+
+  /// The newest snapshot of a calculator.
+  pub fn latest(&self, device_id: &str) -> anyhow::Result<Manifest> {
+    self
+      .snapshots(Some(device_id))?
+      .pop()
+      .ok_or_else(|| anyhow::anyhow!("No snapshots of {}", device_id))
+  }
+
+  /// The files of the newest snapshot of `root` on a calculator, by path.
+  fn previous(
+    &self,
+    device_id: &str,
+    root: &str,
+  ) -> anyhow::Result<HashMap<String, ManifestEntry>> {
+    Ok(
+      self
+        .snapshots(Some(device_id))?
+        .into_iter()
+        .rev()
+        .find(|snapshot| snapshot.root == root)
+        .map(|snapshot| {
+          snapshot
+            .files
+            .into_iter()
+            .map(|entry| (entry.path.clone(), entry))
+            .collect()
+        })
+        .unwrap_or_default(),
+    )
+  }
+
+  fn is_unchanged(&self, previous: &HashMap<String, ManifestEntry>, entry: &TreeEntry) -> bool {
+    match previous.get(&entry.path) {
+      Some(ManifestEntry {
+        size,
+        date,
+        sha256: Some(hash),
+        ..
+      }) => *size == entry.size && *date == entry.date && self.has_object(hash),
+      _ => false,
+    }
+  }
+
+  /// Number of bytes a snapshot of `entries` will have to read.
+  pub fn to_read(
+    &self,
+    device_id: &str,
+    root: &str,
+    entries: &[TreeEntry],
+  ) -> anyhow::Result<usize> {
+    let previous = self.previous(device_id, root)?;
+    Ok(
+      entries
+        .iter()
+        .filter(|entry| !entry.is_dir && !self.is_unchanged(&previous, entry))
+        .map(|entry| entry.size as usize)
+        .sum(),
+    )
+  }
+
+  /// Records a new snapshot of `entries` under `root`. Files whose size and
+  /// date match the previous snapshot of the same folder are not read again.
+  /// `progress` is called with the file being read and the number of bytes
+  /// left to read.
+  pub fn backup(
+    &self,
+    handle: &libnspire::Handle<GlobalContext>,
+    info: &Info,
+    root: &str,
+    entries: &[TreeEntry],
+    progress: &mut dyn FnMut(&str, usize),
+  ) -> anyhow::Result<(Manifest, SnapshotStats)> {
+    let previous = self.previous(&info.id, root)?;
+    let unchanged = |entry: &TreeEntry| self.is_unchanged(&previous, entry);
+
+    let mut manifest = Manifest::new(info, root);
+    let mut stats = SnapshotStats::default();
+    let mut remaining: usize = entries
+      .iter()
+      .filter(|entry| !entry.is_dir && !unchanged(entry))
+      .map(|entry| entry.size as usize)
+      .sum();
+    for entry in entries {
+      if entry.is_dir {
+        manifest.files.push(ManifestEntry {
+          path: entry.path.clone(),
+          is_dir: true,
+          size: 0,
+          date: entry.date,
+          sha256: None,
+        });
+        continue;
+      }
+      if unchanged(entry) {
+        manifest.files.push(previous[&entry.path].clone());
+        stats.unchanged += 1;
+        continue;
+      }
+      let mut buf = vec![0; entry.size as usize];
+      let before = remaining;
+      let res = handle.read_file(&join(root, &entry.path), &mut buf, &mut |left| {
+        progress(
+          &entry.path,
+          before - (entry.size as usize).saturating_sub(left),
+        )
+      });
+      remaining -= entry.size as usize;
+      match res {
+        Ok(len) => buf.truncate(len),
+        Err(libnspire::Error::NoDevice) => return Err(libnspire::Error::NoDevice.into()),
+        Err(error) => {
+          manifest.skipped.push(SkippedEntry {
+            path: entry.path.clone(),
+            error: error.to_string(),
+          });
+          continue;
+        }
+      }
+      let (hash, new) = self.put_object(&buf)?;
+      stats.read += 1;
+      if new {
+        stats.stored += buf.len() as u64;
+      }
+      manifest.files.push(ManifestEntry {
+        path: entry.path.clone(),
+        is_dir: false,
+        size: buf.len() as u64,
+        date: entry.date,
+        sha256: Some(hash),
+      });
+    }
+
+    let path = self.snapshot_path(&manifest.device_id, manifest.created);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    serde_json::to_writer_pretty(File::create(path)?, &manifest)?;
+    Ok((manifest, stats))
+  }
+
+  /// Loads the contents of a snapshot so it can be restored like an archive.
+  pub fn archive(&self, manifest: Manifest) -> anyhow::Result<BackupArchive> {
+    let mut files = HashMap::new();
+    for entry in &manifest.files {
+      if let Some(hash) = &entry.sha256 {
+        files.insert(entry.path.clone(), self.get_object(hash)?);
+      }
+    }
+    Ok(BackupArchive::new(manifest, files))
+  }
+
+  /// Deletes all but the newest `keep` snapshots of each calculator (or only
+  /// of `device_id`), then removes objects no remaining snapshot uses.
+  pub fn prune(&self, device_id: Option<&str>, keep: usize) -> anyhow::Result<PruneResult> {
+    let mut by_device: BTreeMap<String, Vec<Manifest>> = BTreeMap::new();
+    for snapshot in self.snapshots(None)? {
+      by_device
+        .entry(snapshot.device_id.clone())
+        .or_default()
+        .push(snapshot);
+    }
+    let mut result = PruneResult::default();
+    let mut used = HashSet::new();
+    for (id, mut snapshots) in by_device {
+      if device_id.is_none() || device_id == Some(id.as_str()) {
+        let old = snapshots.len().saturating_sub(keep);
+        for snapshot in snapshots.drain(..old) {
+          fs::remove_file(self.snapshot_path(&snapshot.device_id, snapshot.created))?;
+          result.snapshots.push(snapshot);
+        }
+      }
+      for snapshot in snapshots {
+        used.extend(snapshot.files.into_iter().filter_map(|entry| entry.sha256));
+      }
+    }
+
+    for prefix in fs::read_dir(self.dir.join(OBJECTS_DIR))? {
+      let prefix = prefix?;
+      if !prefix.file_type()?.is_dir() {
+        continue;
+      }
+      for object in fs::read_dir(prefix.path())? {
+        let object = object?;
+        let hash = format!(
+          "{}{}",
+          prefix.file_name().to_string_lossy(),
+          object.file_name().to_string_lossy()
+        );
+        if !used.contains(&hash) {
+          result.freed += object.metadata()?.len();
+          result.objects += 1;
+          fs::remove_file(object.path())?;
+        }
+      }
+    }
+    Ok(result)
+  }
+}
@@ -0,0 +1,443 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use rusb::GlobalContext;
+use serde::{Deserialize, Serialize};
+
+use crate::sanitize;
+use crate::tree::{create_calc_parents, is_excluded, join, walk_calc, walk_local, TreeEntry};
+
+/// Name of the file in the local folder remembering the last synced state.
+pub const STATE_FILE: &str = ".n-link-sync.json";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncMode {
+  /// Make the calculator match the local folder.
+  Push,
+  /// Make the local folder match the calculator.
+  Pull,
+  /// Copy changes in both directions.
+  Both,
+}
+
+impl FromStr for SyncMode {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "push" => Ok(SyncMode::Push),
+      "pull" => Ok(SyncMode::Pull),
+      "both" => Ok(SyncMode::Both),
+      other => Err(format!("Unknown sync mode {}", other)),
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ActionKind {
+  Upload,
+  Download,
+  DeleteLocal,
+  DeleteCalc,
+  Conflict,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Action {
+  pub kind: ActionKind,
+  /// Path relative to both sync roots.
+  pub path: String,
+  /// Number of bytes transferred by this action.
+  pub size: u64,
+  /// Why a conflict was reported.
+  pub reason: Option<String>,
+}
+
+impl std::fmt::Display for Action {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self.kind {
+      ActionKind::Upload => write!(f, "upload {}", self.path),
+      ActionKind::Download => write!(f, "download {}", self.path),
+      ActionKind::DeleteLocal => write!(f, "delete local {}", self.path),
+      ActionKind::DeleteCalc => write!(f, "delete calculator {}", self.path),
+      ActionKind::Conflict => write!(
+        f,
+        "conflict {}: {}",
+        self.path,
+        self.reason.as_deref().unwrap_or("")
+      ),
+    }
+  }
+}
+
+pub struct SyncOptions {
+  pub mode: SyncMode,
+  /// Propagate deletions instead of copying the file back.
+  pub delete: bool,
+  pub exclude: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileState {
+  local_size: u64,
+  local_date: u64,
+  calc_size: u64,
+  calc_date: u64,
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct SyncState {
+  remote: String,
+  files: BTreeMap<String, FileState>,
+}
+
+fn load_state(local: &Path, remote: &str) -> SyncState {
+  let state = File::open(local.join(STATE_FILE))
+    .ok()
+    .and_then(|file| serde_json::from_reader::<_, SyncState>(file).ok());
+  match state {
+    Some(state) if state.remote == remote => state,
+    _ => SyncState {
+      remote: remote.to_string(),
+      files: BTreeMap::new(),
+    },
+  }
+}
+
+fn files(entries: Vec<TreeEntry>, exclude: &[String]) -> BTreeMap<String, TreeEntry> {
+  entries
+    .into_iter()
+    .filter(|entry| !entry.is_dir && entry.path != STATE_FILE && !is_excluded(&entry.path, exclude))
+    .map(|entry| (entry.path.clone(), entry))
+    .collect()
+}
+
+/// A computed sync plan, along with the listings it was computed from.
+pub struct Plan {
+  pub actions: Vec<Action>,
+  state: SyncState,
+  calc_dirs: BTreeSet<String>,
+}
+
+/// Compares the local folder against the calculator folder and decides what
+/// needs to be copied or deleted. Files are considered changed when their size
+/// or date differs from what was recorded at the last sync.
+pub fn plan(
+  handle: &libnspire::Handle<GlobalContext>,
+  local: &Path,
+  remote: &str,
+  options: &SyncOptions,
+) -> anyhow::Result<Plan> {
+  let state = load_state(local, remote);
+  let calc_tree = walk_calc(handle, remote)?;
+  let calc_dirs = calc_tree
+    .iter()
+    .filter(|entry| entry.is_dir)
+    .map(|entry| entry.path.clone())
+    .collect();
+  let calc = files(calc_tree, &options.exclude);
+  let local_files = files(walk_local(local)?, &options.exclude);
+  let actions = compare(&local_files, &calc, &state, options);
+  Ok(Plan {
+    actions,
+    state,
+    calc_dirs,
+  })
+}
+
+/// Decides what to do with each file, given both listings and the state
+/// recorded at the last sync.
+fn compare(
+  local_files: &BTreeMap<String, TreeEntry>,
+  calc: &BTreeMap<String, TreeEntry>,
+  state: &SyncState,
+  options: &SyncOptions,
+) -> Vec<Action> {
+  let paths: BTreeSet<&String> = local_files
+    .keys()
+    .chain(calc.keys())
+    .chain(state.files.keys())
+    .collect();
+  let mut actions = vec![];
+  for path in paths {
+    let l = local_files.get(path);
+    let c = calc.get(path);
+    let s = state.files.get(path);
+    let local_changed = match (l, s) {
+      (Some(l), Some(s)) => l.size != s.local_size || l.date != s.local_date,
+      (None, None) => false,
+      _ => true,
+    };
+    let calc_changed = match (c, s) {
+      (Some(c), Some(s)) => c.size != s.calc_size || c.date != s.calc_date,
+      (None, None) => false,
+      _ => true,
+    };
+    let action = |kind, size| Action {
+      kind,
+      path: path.clone(),
+      size,
+      reason: None,
+    };
+    let conflict = |reason: &str| Action {
+      kind: ActionKind::Conflict,
+      path: path.clone(),
+      size: 0,
+      reason: Some(reason.to_string()),
+    };
+    let planned = match (l, c) {
+      (Some(l), Some(c)) => {
+        let differs = match s {
+          Some(_) => local_changed || calc_changed,
+          None => l.size != c.size,
+        };
+        if !differs {
+          None
+        } else {
+          match options.mode {
+            SyncMode::Push => Some(action(ActionKind::Upload, l.size)),
+            SyncMode::Pull => Some(action(ActionKind::Download, c.size)),
+            SyncMode::Both if s.is_none() => Some(conflict("differs on both sides")),
+            SyncMode::Both if local_changed && calc_changed => {
+              Some(conflict("modified on both sides"))
+            }
+            SyncMode::Both if local_changed => Some(action(ActionKind::Upload, l.size)),
+            SyncMode::Both => Some(action(ActionKind::Download, c.size)),
+          }
+        }
+      }
+      (Some(l), None) => match options.mode {
+        SyncMode::Pull if options.delete => Some(action(ActionKind::DeleteLocal, 0)),
+        SyncMode::Pull => None,
+        SyncMode::Both if s.is_some() && options.delete => {
+          if local_changed {
+            Some(conflict("deleted on calculator but modified locally"))
+          } else {
+            Some(action(ActionKind::DeleteLocal, 0))
+          }
+        }
+        _ => Some(action(ActionKind::Upload, l.size)),
+      },
+      (None, Some(c)) => match options.mode {
+        SyncMode::Push if options.delete => Some(action(ActionKind::DeleteCalc, 0)),
+        SyncMode::Push => None,
+        SyncMode::Both if s.is_some() && options.delete => {
+          if calc_changed {
+            Some(conflict("deleted locally but modified on calculator"))
+          } else {
+            Some(action(ActionKind::DeleteCalc, 0))
+          }
+        }
+        _ => Some(action(ActionKind::Download, c.size)),
+      },
+      (None, None) => None,
+    };
+    actions.extend(planned);
+  }
+  actions
+}
+
+/// Performs a single planned action. Conflicts are left untouched.
+pub fn apply(
+  handle: &libnspire::Handle<GlobalContext>,
+  local: &Path,
+  remote: &str,
+  plan: &mut Plan,
+  action: &Action,
+  progress: &mut dyn FnMut(usize),
+) -> anyhow::Result<()> {
+  let local_path = local.join(sanitize::relative_path(&action.path)?);
+  let calc_path = join(remote, &action.path);
+  match action.kind {
+    ActionKind::Upload => {
+      let mut buf = vec![];
+      File::open(&local_path)?.read_to_end(&mut buf)?;
+      create_calc_parents(handle, remote, &action.path, &mut plan.calc_dirs)?;
+      handle.write_file(&calc_path, &buf, progress)?;
+    }
+    ActionKind::Download => {
+      let mut buf = vec![0; action.size as usize];
+      let len = handle.read_file(&calc_path, &mut buf, progress)?;
+      buf.truncate(len);
+      if let Some(parent) = local_path.parent() {
+        fs::create_dir_all(parent)?;
+      }
+      File::create(&local_path)?.write_all(&buf)?;
+    }
+    ActionKind::DeleteLocal => fs::remove_file(&local_path)?,
+    ActionKind::DeleteCalc => handle.delete_file(&calc_path)?,
+    ActionKind::Conflict => {}
+  }
+  Ok(())
+}
+
+/// Records the current state of both sides so the next sync only sees
+/// changes made after this one. Paths in `skipped` (conflicts and failed
+/// actions) keep their previous record.
+pub fn save_state(
+  handle: &libnspire::Handle<GlobalContext>,
+  local: &Path,
+  remote: &str,
+  plan: Plan,
+  skipped: &BTreeSet<String>,
+  exclude: &[String],
+) -> anyhow::Result<()> {
+  let calc = files(walk_calc(handle, remote)?, exclude);
+  let local_files = files(walk_local(local)?, exclude);
+  let mut state = plan.state;
+  let previous = std::mem::take(&mut state.files);
+  for (path, l) in &local_files {
+    if skipped.contains(path) {
+      if let Some(s) = previous.get(path) {
+        state.files.insert(path.clone(), *s);
+      }
+    } else if let Some(c) = calc.get(path) {
+      state.files.insert(
+        path.clone(),
+        FileState {
+          local_size: l.size,
+          local_date: l.date,
+          calc_size: c.size,
+          calc_date: c.date,
+        },
+      );
+    }
+  }
+  serde_json::to_writer_pretty(File::create(local.join(STATE_FILE))?, &state)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(path: &str, size: u64, date: u64) -> TreeEntry {
+    TreeEntry {
+      path: path.to_string(),
+      is_dir: false,
+      size,
+      date,
+    }
+  }
+
+  fn listing(entries: &[TreeEntry]) -> BTreeMap<String, TreeEntry> {
+    files(entries.to_vec(), &[])
+  }
+
+  fn synced(path: &str, local: (u64, u64), calc: (u64, u64)) -> SyncState {
+    let mut state = SyncState::default();
+    state.files.insert(
+      path.to_string(),
+      FileState {
+        local_size: local.0,
+        local_date: local.1,
+        calc_size: calc.0,
+        calc_date: calc.1,
+      },
+    );
+    state
+  }
+
+  fn options(mode: SyncMode, delete: bool) -> SyncOptions {
+    SyncOptions {
+      mode,
+      delete,
+      exclude: vec![],
+    }
+  }
+
+  fn kinds(actions: &[Action]) -> Vec<(ActionKind, &str)> {
+    actions
+      .iter()
+      .map(|action| (action.kind, action.path.as_str()))
+      .collect()
+  }
+
+  #[test]
+  fn first_run_compares_sizes_only() {
+    let local = listing(&[entry("a.tns", 10, 100), entry("b.tns", 10, 100)]);
+    let calc = listing(&[entry("a.tns", 10, 200), entry("b.tns", 20, 100)]);
+    let state = SyncState::default();
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Push, false));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Upload, "b.tns")]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Both, false));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Conflict, "b.tns")]);
+  }
+
+  #[test]
+  fn copies_the_side_that_changed() {
+    let state = synced("a.tns", (10, 100), (10, 100));
+    let calc = listing(&[entry("a.tns", 10, 100)]);
+    let unchanged = listing(&[entry("a.tns", 10, 100)]);
+    let both = options(SyncMode::Both, false);
+    assert!(compare(&unchanged, &calc, &state, &both).is_empty());
+
+    let local = listing(&[entry("a.tns", 10, 150)]);
+    let actions = compare(&local, &calc, &state, &both);
+    assert_eq!(kinds(&actions), vec![(ActionKind::Upload, "a.tns")]);
+
+    let calc_changed = listing(&[entry("a.tns", 12, 100)]);
+    let actions = compare(&unchanged, &calc_changed, &state, &both);
+    assert_eq!(kinds(&actions), vec![(ActionKind::Download, "a.tns")]);
+    assert_eq!(actions[0].size, 12);
+  }
+
+  #[test]
+  fn reports_changes_on_both_sides_as_conflicts() {
+    let state = synced("a.tns", (10, 100), (10, 100));
+    let local = listing(&[entry("a.tns", 11, 100)]);
+    let calc = listing(&[entry("a.tns", 12, 100)]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Both, false));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Conflict, "a.tns")]);
+    assert_eq!(actions[0].reason.as_deref(), Some("modified on both sides"));
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Pull, false));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Download, "a.tns")]);
+  }
+
+  #[test]
+  fn propagates_deletions_only_when_asked() {
+    let state = synced("a.tns", (10, 100), (10, 100));
+    let local = listing(&[]);
+    let calc = listing(&[entry("a.tns", 10, 100)]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Both, true));
+    assert_eq!(kinds(&actions), vec![(ActionKind::DeleteCalc, "a.tns")]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Both, false));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Download, "a.tns")]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Push, true));
+    assert_eq!(kinds(&actions), vec![(ActionKind::DeleteCalc, "a.tns")]);
+    assert!(compare(&local, &calc, &state, &options(SyncMode::Push, false)).is_empty());
+
+    let actions = compare(&calc, &local, &state, &options(SyncMode::Pull, true));
+    assert_eq!(kinds(&actions), vec![(ActionKind::DeleteLocal, "a.tns")]);
+  }
+
+  #[test]
+  fn never_deletes_a_modified_file() {
+    let state = synced("a.tns", (10, 100), (10, 100));
+    let local = listing(&[]);
+    let calc = listing(&[entry("a.tns", 20, 100)]);
+    let actions = compare(&local, &calc, &state, &options(SyncMode::Both, true));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Conflict, "a.tns")]);
+    let actions = compare(&calc, &local, &state, &options(SyncMode::Both, true));
+    assert_eq!(kinds(&actions), vec![(ActionKind::Conflict, "a.tns")]);
+  }
+
+  #[test]
+  fn ignores_excluded_files_and_state_file() {
+    let exclude = vec!["*.bak".to_string()];
+    let entries = vec![
+      entry("a.tns", 1, 1),
+      entry("old/a.bak", 1, 1),
+      entry(STATE_FILE, 1, 1),
+    ];
+    let kept: Vec<_> = files(entries, &exclude).keys().cloned().collect();
+    assert_eq!(kept, vec!["a.tns"]);
+  }
+}
@@ -0,0 +1,613 @@
+use std::fs;
+use std::io::{self, Read, Stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::Show;
+use crossterm::event::{self, Event, KeyCode, KeyEvent};
+use crossterm::execute;
+use crossterm::terminal::{
+  disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use indicatif::HumanBytes;
+use libnspire::dir::EntryType;
+use libnspire::info::Info;
+use rusb::GlobalContext;
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph};
+use tui::{Frame, Terminal};
+
+use crate::nspire_path::NspirePath;
+use crate::sanitize;
+
+type Handle = libnspire::Handle<GlobalContext>;
+type Term = Terminal<CrosstermBackend<Stdout>>;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Side {
+  Local,
+  Calc,
+}
+
+impl Side {
+  fn other(self) -> Side {
+    match self {
+      Side::Local => Side::Calc,
+      Side::Calc => Side::Local,
+    }
+  }
+}
+
+#[derive(Clone)]
+struct Entry {
+  name: String,
+  is_dir: bool,
+  size: u64,
+}
+
+struct Pane {
+  entries: Vec<Entry>,
+  state: ListState,
+}
+
+impl Pane {
+  fn new() -> Self {
+    Pane {
+      entries: vec![],
+      state: ListState::default(),
+    }
+  }
+
+  fn set_entries(&mut self, mut entries: Vec<Entry>) {
+    entries.sort_by(|a, b| {
+      b.is_dir
+        .cmp(&a.is_dir)
+        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    let selected = self.state.selected().unwrap_or(0);
+    self.entries = entries;
+    self.state.select(if self.entries.is_empty() {
+      None
+    } else {
+      Some(selected.min(self.entries.len() - 1))
+    });
+  }
+
+  fn selected(&self) -> Option<&Entry> {
+    self.state.selected().and_then(|i| self.entries.get(i))
+  }
+
+  fn move_by(&mut self, delta: isize) {
+    if self.entries.is_empty() {
+      return;
+    }
+    let current = self.state.selected().unwrap_or(0) as isize;
+    let next = (current + delta)
+      .max(0)
+      .min(self.entries.len() as isize - 1);
+    self.state.select(Some(next as usize));
+  }
+}
+
+enum Prompt {
+  Mkdir(String),
+  Delete(Side, Entry),
+}
+
+struct Transfer {
+  label: String,
+  done: usize,
+  total: usize,
+}
+
+struct App {
+  info: Option<Info>,
+  local_dir: PathBuf,
+  calc_dir: NspirePath,
+  local: Pane,
+  calc: Pane,
+  focus: Side,
+  prompt: Option<Prompt>,
+  transfer: Option<Transfer>,
+  status: String,
+}
+
+fn list_local(dir: &Path) -> io::Result<Vec<Entry>> {
+  let mut entries = vec![];
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    let meta = entry.metadata()?;
+    entries.push(Entry {
+      name: entry.file_name().to_string_lossy().to_string(),
+      is_dir: meta.is_dir(),
+      size: meta.len(),
+    });
+  }
+  Ok(entries)
+}
+
+fn list_calc(handle: &Handle, dir: &str) -> libnspire::Result<Vec<Entry>> {
+  Ok(
+    handle
+      .list_dir(dir)?
+      .iter()
+      .map(|item| Entry {
+        name: item.name().to_string_lossy().to_string(),
+        is_dir: item.entry_type() == EntryType::Directory,
+        size: item.size(),
+      })
+      .collect(),
+  )
+}
+
+impl App {
+  fn pane_mut(&mut self, side: Side) -> &mut Pane {
+    match side {
+      Side::Local => &mut self.local,
+      Side::Calc => &mut self.calc,
+    }
+  }
+
+  fn refresh(&mut self, handle: &Handle) {
+    match list_local(&self.local_dir) {
+      Ok(entries) => self.local.set_entries(entries),
+      Err(error) => self.status = format!("Failed to list local directory: {}", error),
+    }
+    match list_calc(handle, &self.calc_dir) {
+      Ok(entries) => self.calc.set_entries(entries),
+      Err(error) => self.status = format!("Failed to list calculator directory: {}", error),
+    }
+  }
+
+  fn refresh_info(&mut self, handle: &Handle) {
+    match handle.info() {
+      Ok(info) => self.info = Some(info),
+      Err(error) => self.status = format!("Failed to read device info: {}", error),
+    }
+  }
+
+  fn enter(&mut self, handle: &Handle) {
+    let side = self.focus;
+    let entry = match self.pane_mut(side).selected() {
+      Some(entry) if entry.is_dir => entry.clone(),
+      _ => return,
+    };
+    match side {
+      Side::Local => self.local_dir = self.local_dir.join(&entry.name),
+      Side::Calc => match self.calc_dir.join(&entry.name) {
+        Ok(dir) => self.calc_dir = dir,
+        Err(error) => {
+          self.status = error.to_string();
+          return;
+        }
+      },
+    }
+    self.pane_mut(side).state.select(Some(0));
+    self.refresh(handle);
+  }
+
+  fn leave(&mut self, handle: &Handle) {
+    match self.focus {
+      Side::Local => {
+        if let Some(parent) = self.local_dir.parent() {
+          self.local_dir = parent.to_path_buf();
+        }
+      }
+      Side::Calc => {
+        if let Some(parent) = self.calc_dir.parent() {
+          self.calc_dir = parent;
+        }
+      }
+    }
+    self.pane_mut(self.focus).state.select(Some(0));
+    self.refresh(handle);
+  }
+}
+
+fn draw_pane<B: tui::backend::Backend>(
+  f: &mut Frame<B>,
+  area: Rect,
+  title: String,
+  pane: &mut Pane,
+  focused: bool,
+) {
+  let items: Vec<_> = pane
+    .entries
+    .iter()
+    .map(|entry| {
+      if entry.is_dir {
+        ListItem::new(Spans::from(vec![Span::styled(
+          format!("{}/", entry.name),
+          Style::default().fg(Color::Blue),
+        )]))
+      } else {
+        ListItem::new(format!("{} ({})", entry.name, HumanBytes(entry.size)))
+      }
+    })
+    .collect();
+  let border = if focused {
+    Style::default().fg(Color::Cyan)
+  } else {
+    Style::default()
+  };
+  let list = List::new(items)
+    .block(
+      Block::default()
+        .borders(Borders::ALL)
+        .border_style(border)
+        .title(title),
+    )
+    .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+  f.render_stateful_widget(list, area, &mut pane.state);
+}
+
+fn draw(f: &mut Frame<CrosstermBackend<Stdout>>, app: &mut App) {
+  let chunks = Layout::default()
+    .direction(Direction::Vertical)
+    .constraints([
+      Constraint::Min(5),
+      Constraint::Length(5),
+      Constraint::Length(3),
+      Constraint::Length(1),
+    ])
+    .split(f.size());
+  let panes = Layout::default()
+    .direction(Direction::Horizontal)
+    .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+    .split(chunks[0]);
+
+  let local_title = format!("Local: {}", app.local_dir.display());
+  let calc_title = format!("Calculator: {}", app.calc_dir);
+  let focus = app.focus;
+  draw_pane(
+    f,
+    panes[0],
+    local_title,
+    &mut app.local,
+    focus == Side::Local,
+  );
+  draw_pane(f, panes[1], calc_title, &mut app.calc, focus == Side::Calc);
+
+  let info = match &app.info {
+    Some(info) => vec![
+      Spans::from(format!(
+        "{} ({:?}), ID {}, OS {}",
+        info.name, info.hw_type, info.id, info.version
+      )),
+      Spans::from(format!(
+        "Battery: {:?}{}",
+        info.battery,
+        if info.is_charging { " (charging)" } else { "" }
+      )),
+      Spans::from(format!(
+        "Storage: {} free of {}, RAM: {} free of {}",
+        HumanBytes(info.free_storage),
+        HumanBytes(info.total_storage),
+        HumanBytes(info.free_ram),
+        HumanBytes(info.total_ram)
+      )),
+    ],
+    None => vec![Spans::from("No device info")],
+  };
+  f.render_widget(
+    Paragraph::new(info).block(Block::default().borders(Borders::ALL).title("Device")),
+    chunks[1],
+  );
+
+  let gauge = Block::default().borders(Borders::ALL).title("Transfer");
+  match &app.transfer {
+    Some(transfer) => {
+      let ratio = if transfer.total == 0 {
+        1.0
+      } else {
+        transfer.done as f64 / transfer.total as f64
+      };
+      f.render_widget(
+        Gauge::default()
+          .block(gauge)
+          .gauge_style(Style::default().fg(Color::Cyan))
+          .ratio(ratio.min(1.0))
+          .label(format!(
+            "{} {}/{}",
+            transfer.label,
+            HumanBytes(transfer.done as u64),
+            HumanBytes(transfer.total as u64)
+          )),
+        chunks[2],
+      );
+    }
+    None => f.render_widget(Paragraph::new("Idle").block(gauge), chunks[2]),
+  }
+
+  let status = match &app.prompt {
+    Some(Prompt::Mkdir(name)) => format!("New directory name: {}_", name),
+    Some(Prompt::Delete(_, entry)) => format!("Delete {}? (y/n)", entry.name),
+    None if app.status.is_empty() => {
+      "Tab: switch  Enter: open  Backspace: up  c: copy  m: move  d: delete  n: mkdir  r: refresh  q: quit".to_string()
+    }
+    None => app.status.clone(),
+  };
+  f.render_widget(Paragraph::new(status), chunks[3]);
+}
+
+/// Redraws the screen while a transfer is running, at most every 50ms.
+fn progress<'a>(
+  terminal: &'a mut Term,
+  app: &'a mut App,
+  label: String,
+  total: usize,
+) -> impl FnMut(usize) + 'a {
+  app.transfer = Some(Transfer {
+    label,
+    done: 0,
+    total,
+  });
+  let mut last_draw: Option<Instant> = None;
+  move |remaining| {
+    if let Some(transfer) = &mut app.transfer {
+      transfer.done = total - remaining.min(total);
+    }
+    let due = match last_draw {
+      Some(time) => time.elapsed() > Duration::from_millis(50),
+      None => true,
+    };
+    if due || remaining == 0 {
+      last_draw = Some(Instant::now());
+      let _ = terminal.draw(|f| draw(f, app));
+    }
+  }
+}
+
+fn upload(
+  handle: &Handle,
+  terminal: &mut Term,
+  app: &mut App,
+  src: &Path,
+  dest: &NspirePath,
+) -> anyhow::Result<()> {
+  if src.is_dir() {
+    handle.create_dir(dest)?;
+    for entry in list_local(src)? {
+      upload(
+        handle,
+        terminal,
+        app,
+        &src.join(&entry.name),
+        &dest.join(&entry.name)?,
+      )?;
+    }
+  } else {
+    let mut buf = vec![];
+    fs::File::open(src)?.read_to_end(&mut buf)?;
+    let label = format!("Upload {}", dest);
+    handle.write_file(dest, &buf, &mut progress(terminal, app, label, buf.len()))?;
+  }
+  Ok(())
+}
+
+fn download(
+  handle: &Handle,
+  terminal: &mut Term,
+  app: &mut App,
+  src: &NspirePath,
+  entry: &Entry,
+  dest: &Path,
+) -> anyhow::Result<()> {
+  if entry.is_dir {
+    fs::create_dir_all(dest)?;
+    for child in list_calc(handle, src)? {
+      download(
+        handle,
+        terminal,
+        app,
+        &src.join(&child.name)?,
+        &child,
+        &dest.join(sanitize::file_name(&child.name)?),
+      )?;
+    }
+  } else {
+    let mut buf = vec![0; entry.size as usize];
+    let label = format!("Download {}", src);
+    let total = buf.len();
+    handle.read_file(src, &mut buf, &mut progress(terminal, app, label, total))?;
+    fs::File::create(dest)?.write_all(&buf)?;
+  }
+  Ok(())
+}
+
+fn delete_calc(handle: &Handle, path: &NspirePath, is_dir: bool) -> anyhow::Result<()> {
+  if is_dir {
+    for child in list_calc(handle, path)? {
+      delete_calc(handle, &path.join(&child.name)?, child.is_dir)?;
+    }
+    handle.delete_dir(path)?;
+  } else {
+    handle.delete_file(path)?;
+  }
+  Ok(())
+}
+
+fn delete(handle: &Handle, app: &App, side: Side, entry: &Entry) -> anyhow::Result<()> {
+  match side {
+    Side::Local => {
+      let path = app.local_dir.join(&entry.name);
+      if entry.is_dir {
+        fs::remove_dir_all(path)?;
+      } else {
+        fs::remove_file(path)?;
+      }
+    }
+    Side::Calc => delete_calc(handle, &app.calc_dir.join(&entry.name)?, entry.is_dir)?,
+  }
+  Ok(())
+}
+
+/// Copies the selected entry into the directory shown in the other pane,
+/// removing the source afterwards if `remove` is set.
+fn transfer(
+  handle: &Handle,
+  terminal: &mut Term,
+  app: &mut App,
+  remove: bool,
+) -> anyhow::Result<String> {
+  let side = app.focus;
+  let entry = match app.pane_mut(side).selected() {
+    Some(entry) => entry.clone(),
+    None => anyhow::bail!("Nothing selected"),
+  };
+  let res = match side {
+    Side::Local => {
+      let src = app.local_dir.join(&entry.name);
+      let dest = app.calc_dir.join(&entry.name)?;
+      upload(handle, terminal, app, &src, &dest)
+    }
+    Side::Calc => {
+      let src = app.calc_dir.join(&entry.name)?;
+      let dest = app.local_dir.join(sanitize::file_name(&entry.name)?);
+      download(handle, terminal, app, &src, &entry, &dest)
+    }
+  };
+  app.transfer = None;
+  res?;
+  if remove {
+    delete(handle, app, side, &entry)?;
+    Ok(format!("Moved {}", entry.name))
+  } else {
+    Ok(format!("Copied {}", entry.name))
+  }
+}
+
+fn handle_prompt(handle: &Handle, app: &mut App, key: KeyEvent) {
+  let prompt = match app.prompt.take() {
+    Some(prompt) => prompt,
+    None => return,
+  };
+  match prompt {
+    Prompt::Mkdir(mut name) => match key.code {
+      KeyCode::Enter if !name.is_empty() => {
+        let res = match app.focus {
+          Side::Local => fs::create_dir(app.local_dir.join(&name)).map_err(anyhow::Error::from),
+          Side::Calc => app
+            .calc_dir
+            .join(&name)
+            .map_err(anyhow::Error::from)
+            .and_then(|path| handle.create_dir(&path).map_err(anyhow::Error::from)),
+        };
+        app.status = match res {
+          Ok(_) => format!("Created {}", name),
+          Err(error) => format!("Failed to create directory: {}", error),
+        };
+        app.refresh(handle);
+      }
+      KeyCode::Esc => {}
+      KeyCode::Backspace => {
+        name.pop();
+        app.prompt = Some(Prompt::Mkdir(name));
+      }
+      KeyCode::Char(c) => {
+        name.push(c);
+        app.prompt = Some(Prompt::Mkdir(name));
+      }
+      _ => app.prompt = Some(Prompt::Mkdir(name)),
+    },
+    Prompt::Delete(side, entry) => {
+      if let KeyCode::Char('y') | KeyCode::Char('Y') = key.code {
+        app.status = match delete(handle, app, side, &entry) {
+          Ok(_) => format!("Deleted {}", entry.name),
+          Err(error) => format!("Failed to delete: {}", error),
+        };
+        app.refresh(handle);
+        app.refresh_info(handle);
+      }
+    }
+  }
+}
+
+fn event_loop(handle: &Handle, terminal: &mut Term, app: &mut App) -> anyhow::Result<()> {
+  loop {
+    terminal.draw(|f| draw(f, app))?;
+    let key = match event::read()? {
+      Event::Key(key) => key,
+      _ => continue,
+    };
+    if app.prompt.is_some() {
+      handle_prompt(handle, app, key);
+      continue;
+    }
+    app.status.clear();
+    match key.code {
+      KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+      KeyCode::Tab | KeyCode::Left | KeyCode::Right => app.focus = app.focus.other(),
+      KeyCode::Up | KeyCode::Char('k') => app.pane_mut(app.focus).move_by(-1),
+      KeyCode::Down | KeyCode::Char('j') => app.pane_mut(app.focus).move_by(1),
+      KeyCode::PageUp => app.pane_mut(app.focus).move_by(-10),
+      KeyCode::PageDown => app.pane_mut(app.focus).move_by(10),
+      KeyCode::Enter => app.enter(handle),
+      KeyCode::Backspace => app.leave(handle),
+      KeyCode::Char('r') => {
+        app.refresh(handle);
+        app.refresh_info(handle);
+      }
+      KeyCode::Char('n') | KeyCode::F(7) => app.prompt = Some(Prompt::Mkdir(String::new())),
+      KeyCode::Char('d') | KeyCode::Delete | KeyCode::F(8) => {
+        if let Some(entry) = app.pane_mut(app.focus).selected().cloned() {
+          app.prompt = Some(Prompt::Delete(app.focus, entry));
+        }
+      }
+      KeyCode::Char('c') | KeyCode::Char('m') | KeyCode::F(5) | KeyCode::F(6) => {
+        let remove = matches!(key.code, KeyCode::Char('m') | KeyCode::F(6));
+        app.status = match transfer(handle, terminal, app, remove) {
+          Ok(msg) => msg,
+          Err(error) => format!("Transfer failed: {}", error),
+        };
+        app.refresh(handle);
+        app.refresh_info(handle);
+      }
+      _ => {}
+    }
+  }
+}
+
+/// Runs the full-screen file manager until the user quits.
+pub fn run(handle: Handle, local_dir: PathBuf, calc_dir: NspirePath) -> anyhow::Result<()> {
+  let mut app = App {
+    info: None,
+    local_dir,
+    calc_dir,
+    local: Pane::new(),
+    calc: Pane::new(),
+    focus: Side::Local,
+    prompt: None,
+    transfer: None,
+    status: String::new(),
+  };
+  app.refresh(&handle);
+  app.refresh_info(&handle);
+
+  let _guard = RawScreen::enter()?;
+  let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+  event_loop(&handle, &mut terminal, &mut app)
+}
+
+/// Keeps the terminal in raw mode on the alternate screen, and restores it
+/// when dropped, including on an early return or a panic.
+struct RawScreen;
+
+impl RawScreen {
+  fn enter() -> anyhow::Result<Self> {
+    enable_raw_mode()?;
+    let guard = RawScreen;
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    Ok(guard)
+  }
+}
+
+impl Drop for RawScreen {
+  fn drop(&mut self) {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, Show);
+  }
+}
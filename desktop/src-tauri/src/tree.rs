@@ -0,0 +1,270 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use libnspire::dir::EntryType;
+use rusb::GlobalContext;
+use serde::{Deserialize, Serialize};
+
+/// A file or directory found while walking a tree, with a `/`-separated path
+/// relative to the root of the walk.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TreeEntry {
+  pub path: String,
+  pub is_dir: bool,
+  pub size: u64,
+  /// Seconds since the Unix epoch.
+  pub date: u64,
+}
+
+pub fn join(dir: &str, name: &str) -> String {
+  if dir.is_empty() {
+    name.to_string()
+  } else {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+  }
+}
+
+/// Recursively lists a directory on the calculator. Directories are listed
+/// before their contents.
+pub fn walk_calc(
+  handle: &libnspire::Handle<GlobalContext>,
+  root: &str,
+) -> libnspire::Result<Vec<TreeEntry>> {
+  let mut entries = vec![];
+  walk_calc_into(handle, root, "", &mut entries)?;
+  Ok(entries)
+}
+
+fn walk_calc_into(
+  handle: &libnspire::Handle<GlobalContext>,
+  root: &str,
+  rel: &str,
+  entries: &mut Vec<TreeEntry>,
+) -> libnspire::Result<()> {
+  let dir = handle.list_dir(&join(root, rel))?;
+  for item in dir.iter() {
+    let path = join(rel, &item.name().to_string_lossy());
+    let is_dir = item.entry_type() == EntryType::Directory;
+    entries.push(TreeEntry {
+      path: path.clone(),
+      is_dir,
+      size: item.size(),
+      date: item.date(),
+    });
+    if is_dir {
+      walk_calc_into(handle, root, &path, entries)?;
+    }
+  }
+  Ok(())
+}
+
+/// Creates the parent directories of `path`, relative to `root`, on the
+/// calculator. `known` holds directories that already exist and is updated with
+/// the ones created.
+pub fn create_calc_parents(
+  handle: &libnspire::Handle<GlobalContext>,
+  root: &str,
+  path: &str,
+  known: &mut BTreeSet<String>,
+) -> libnspire::Result<()> {
+  let mut dir = String::new();
+  let parts: Vec<_> = path.split('/').collect();
+  for part in &parts[..parts.len() - 1] {
+    dir = join(&dir, part);
+    if !known.contains(&dir) {
+      match handle.create_dir(&join(root, &dir)) {
+        Ok(_) | Err(libnspire::Error::Exists) => {}
+        Err(e) => return Err(e),
+      }
+      known.insert(dir.clone());
+    }
+  }
+  Ok(())
+}
+
+/// Deletes a file, or a directory with everything in it, on the calculator.
+pub fn delete_calc(handle: &libnspire::Handle<GlobalContext>, path: &str) -> libnspire::Result<()> {
+  if handle.file_attr(path)?.entry_type() != EntryType::Directory {
+    return handle.delete_file(path);
+  }
+  for entry in walk_calc(handle, path)?.iter().rev() {
+    let entry_path = join(path, &entry.path);
+    if entry.is_dir {
+      handle.delete_dir(&entry_path)?;
+    } else {
+      handle.delete_file(&entry_path)?;
+    }
+  }
+  handle.delete_dir(path)
+}
+
+/// Recursively lists a local directory. Directories are listed before their
+/// contents.
+pub fn walk_local(root: &Path) -> io::Result<Vec<TreeEntry>> {
+  let mut entries = vec![];
+  walk_local_into(root, "", &mut entries)?;
+  Ok(entries)
+}
+
+fn walk_local_into(root: &Path, rel: &str, entries: &mut Vec<TreeEntry>) -> io::Result<()> {
+  for entry in fs::read_dir(root.join(rel))? {
+    let entry = entry?;
+    let meta = entry.metadata()?;
+    let path = join(rel, &entry.file_name().to_string_lossy());
+    let date = meta
+      .modified()
+      .ok()
+      .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+      .map_or(0, |time| time.as_secs());
+    entries.push(TreeEntry {
+      path: path.clone(),
+      is_dir: meta.is_dir(),
+      size: if meta.is_dir() { 0 } else { meta.len() },
+      date,
+    });
+    if meta.is_dir() {
+      walk_local_into(root, &path, entries)?;
+    }
+  }
+  Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+  Created,
+  Modified,
+  Deleted,
+}
+
+/// Compares two listings of the same tree. Entries are considered modified
+/// when their size or date changed.
+pub fn changes(old: &[TreeEntry], new: &[TreeEntry]) -> Vec<(ChangeKind, TreeEntry)> {
+  let old_paths: HashMap<_, _> = old.iter().map(|entry| (&entry.path, entry)).collect();
+  let new_paths: HashSet<_> = new.iter().map(|entry| &entry.path).collect();
+  let mut changes = vec![];
+  for entry in new {
+    match old_paths.get(&entry.path) {
+      None => changes.push((ChangeKind::Created, entry.clone())),
+      Some(old) if !entry.is_dir && (old.size != entry.size || old.date != entry.date) => {
+        changes.push((ChangeKind::Modified, entry.clone()))
+      }
+      Some(_) => {}
+    }
+  }
+  for entry in old {
+    if !new_paths.contains(&entry.path) {
+      changes.push((ChangeKind::Deleted, entry.clone()));
+    }
+  }
+  changes
+}
+
+/// Matches `text` against a shell-style pattern: `*` matches anything but `/`,
+/// `**` matches anything and `?` matches a single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+  fn matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+      None => text.is_empty(),
+      Some(('*', rest)) if rest.first() == Some(&'*') => {
+        let rest = &rest[1..];
+        (0..=text.len()).any(|i| matches(rest, &text[i..]))
+      }
+      Some(('*', rest)) => {
+        for i in 0..=text.len() {
+          if matches(rest, &text[i..]) {
+            return true;
+          }
+          if text.get(i) == Some(&'/') {
+            break;
+          }
+        }
+        false
+      }
+      Some(('?', rest)) => {
+        matches!(text.split_first(), Some((c, text)) if *c != '/' && matches(rest, text))
+      }
+      Some((p, rest)) => {
+        matches!(text.split_first(), Some((c, text)) if c == p && matches(rest, text))
+      }
+    }
+  }
+  let pattern: Vec<char> = pattern.chars().collect();
+  let text: Vec<char> = text.chars().collect();
+  matches(&pattern, &text)
+}
+
+/// Whether a relative path is excluded by any of the patterns, either as a
+/// whole or by one of its components.
+pub fn is_excluded(path: &str, patterns: &[String]) -> bool {
+  patterns.iter().any(|pattern| {
+    glob_match(pattern, path) || path.split('/').any(|part| glob_match(pattern, part))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(path: &str, is_dir: bool, size: u64, date: u64) -> TreeEntry {
+    TreeEntry {
+      path: path.to_string(),
+      is_dir,
+      size,
+      date,
+    }
+  }
+
+  #[test]
+  fn finds_created_modified_and_deleted_entries() {
+    let old = vec![
+      entry("dir", true, 0, 1),
+      entry("dir/a.tns", false, 10, 1),
+      entry("b.tns", false, 10, 1),
+      entry("c.tns", false, 10, 1),
+    ];
+    let new = vec![
+      entry("dir", true, 0, 5),
+      entry("dir/a.tns", false, 10, 2),
+      entry("c.tns", false, 10, 1),
+      entry("d.tns", false, 3, 1),
+    ];
+    let found: Vec<_> = changes(&old, &new)
+      .into_iter()
+      .map(|(kind, entry)| (kind, entry.path))
+      .collect();
+    assert_eq!(
+      found,
+      vec![
+        (ChangeKind::Modified, "dir/a.tns".to_string()),
+        (ChangeKind::Created, "d.tns".to_string()),
+        (ChangeKind::Deleted, "b.tns".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn matches_globs() {
+    assert!(glob_match("*.tns", "quiz.tns"));
+    assert!(!glob_match("*.tns", "dir/quiz.tns"));
+    assert!(glob_match("**.tns", "dir/quiz.tns"));
+    assert!(glob_match("dir/**", "dir/a/b.tns"));
+    assert!(glob_match("quiz?.tns", "quiz1.tns"));
+    assert!(!glob_match("quiz?.tns", "quiz.tns"));
+    assert!(!glob_match("a?b", "a/b"));
+    assert!(!glob_match("*.tns", "quiz.tns.bak"));
+  }
+
+  #[test]
+  fn excludes_paths_by_any_component() {
+    let patterns = vec![".git".to_string(), "*.bak".to_string()];
+    assert!(is_excluded(".git/config", &patterns));
+    assert!(is_excluded("dir/old.bak", &patterns));
+    assert!(!is_excluded("dir/quiz.tns", &patterns));
+    assert!(!is_excluded("dir/quiz.tns", &[]));
+  }
+}
@@ -0,0 +1,47 @@
+use rusb::GlobalContext;
+
+use crate::backup::sha256;
+
+/// Reads `path` back from the calculator and compares its SHA-256 with the
+/// data that was written to it.
+pub fn verify_upload(
+  handle: &libnspire::Handle<GlobalContext>,
+  path: &str,
+  data: &[u8],
+) -> anyhow::Result<()> {
+  let size = handle.file_attr(path)?.size();
+  if size != data.len() as u64 {
+    anyhow::bail!(
+      "{} is {} bytes on the calculator instead of {}",
+      path,
+      size,
+      data.len()
+    );
+  }
+  let mut buf = vec![0; data.len()];
+  let len = handle.read_file(path, &mut buf, &mut |_| {})?;
+  buf.truncate(len);
+  if sha256(&buf) != sha256(data) {
+    anyhow::bail!("{} on the calculator doesn't match what was sent", path);
+  }
+  Ok(())
+}
+
+/// Checks that `len` bytes, the amount received, is the size the calculator
+/// reports for `path`.
+pub fn verify_download(
+  handle: &libnspire::Handle<GlobalContext>,
+  path: &str,
+  len: usize,
+) -> anyhow::Result<()> {
+  let size = handle.file_attr(path)?.size();
+  if size != len as u64 {
+    anyhow::bail!(
+      "Received {} bytes of {}, but it is {} bytes on the calculator",
+      len,
+      path,
+      size
+    );
+  }
+  Ok(())
+}
@@ -0,0 +1,157 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+use indicatif::HumanBytes;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use rusb::GlobalContext;
+
+use crate::tree::{create_calc_parents, is_excluded, join};
+
+type Handle = libnspire::Handle<GlobalContext>;
+
+/// Where a changed local file should be uploaded, relative to the remote root.
+fn relative_path(root: &Path, is_file: bool, path: &Path) -> Option<String> {
+  if is_file {
+    return root
+      .file_name()
+      .map(|name| name.to_string_lossy().to_string());
+  }
+  let rel = path
+    .strip_prefix(root)
+    .ok()
+    .map(Path::to_path_buf)
+    .or_else(|| {
+      path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.strip_prefix(root).ok().map(Path::to_path_buf))
+    })?;
+  let parts: Vec<_> = rel
+    .components()
+    .map(|part| part.as_os_str().to_string_lossy().to_string())
+    .collect();
+  if parts.is_empty() {
+    None
+  } else {
+    Some(parts.join("/"))
+  }
+}
+
+fn push(
+  handle: &Handle,
+  src: &Path,
+  remote: &str,
+  rel: &str,
+  dirs: &mut BTreeSet<String>,
+) -> anyhow::Result<usize> {
+  let mut buf = vec![];
+  File::open(src)?.read_to_end(&mut buf)?;
+  create_calc_parents(handle, remote, rel, dirs)?;
+  handle.write_file(&join(remote, rel), &buf, &mut |_| {})?;
+  Ok(buf.len())
+}
+
+fn is_disconnect(error: &anyhow::Error) -> bool {
+  matches!(
+    error.downcast_ref(),
+    Some(libnspire::Error::NoDevice)
+      | Some(libnspire::Error::LibUsb)
+      | Some(libnspire::Error::Usb(_))
+  )
+}
+
+/// Watches `local` (a file or a directory) and uploads every changed file to
+/// `remote` until interrupted. When the calculator is unplugged, changes are
+/// queued and uploaded once `connect` finds it again.
+pub fn run(
+  local: &Path,
+  remote: &str,
+  delay: Duration,
+  exclude: &[String],
+  connect: &dyn Fn() -> Option<Handle>,
+) -> anyhow::Result<()> {
+  let root = local.canonicalize()?;
+  let is_file = root.is_file();
+  let (tx, rx) = channel();
+  let mut watcher = watcher(tx, delay)?;
+  watcher.watch(
+    &root,
+    if is_file {
+      RecursiveMode::NonRecursive
+    } else {
+      RecursiveMode::Recursive
+    },
+  )?;
+  println!("Watching {} for changes", root.display());
+
+  let mut handle = connect();
+  if handle.is_none() {
+    println!("Waiting for a calculator to be connected");
+  }
+  let mut dirs = BTreeSet::new();
+  let mut pending: HashSet<PathBuf> = HashSet::new();
+  loop {
+    match rx.recv_timeout(Duration::from_secs(1)) {
+      Ok(DebouncedEvent::Create(path)) | Ok(DebouncedEvent::Write(path)) => {
+        pending.insert(path);
+      }
+      Ok(DebouncedEvent::Rename(_, path)) => {
+        pending.insert(path);
+      }
+      Ok(DebouncedEvent::Error(error, _)) => eprintln!("Watch error: {}", error),
+      Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+      Err(RecvTimeoutError::Disconnected) => return Ok(()),
+    }
+    if handle.is_none() && !pending.is_empty() {
+      handle = connect();
+      if handle.is_some() {
+        println!("Calculator connected");
+        dirs.clear();
+      }
+    }
+    let dev = match handle.take() {
+      Some(dev) => dev,
+      None => continue,
+    };
+    let mut connected = true;
+    for path in pending.clone() {
+      if !path.is_file() {
+        pending.remove(&path);
+        continue;
+      }
+      let rel = match relative_path(&root, is_file, &path) {
+        Some(rel) if !is_excluded(&rel, exclude) => rel,
+        _ => {
+          pending.remove(&path);
+          continue;
+        }
+      };
+      match push(&dev, &path, remote, &rel, &mut dirs) {
+        Ok(len) => {
+          println!(
+            "Uploaded {} ({})",
+            join(remote, &rel),
+            HumanBytes(len as u64)
+          );
+          pending.remove(&path);
+        }
+        Err(error) if is_disconnect(&error) => {
+          println!("Calculator disconnected, waiting for it to come back");
+          connected = false;
+          break;
+        }
+        Err(error) => {
+          eprintln!("Failed to upload {}: {}", join(remote, &rel), error);
+          pending.remove(&path);
+        }
+      }
+    }
+    if connected {
+      handle = Some(dev);
+    }
+  }
+}
@@ -1,6 +1,6 @@
 import {invoke} from '@tauri-apps/api/tauri';
 import {listen} from '@tauri-apps/api/event';
-import {open as openDialog} from '@tauri-apps/api/dialog';
+import {open as openDialog, save as saveDialog} from '@tauri-apps/api/dialog';
 import {Component, Vue} from 'vue-property-decorator';
 import type {GenericDevices} from 'n-link-core/components/devices';
 
@@ -39,9 +39,22 @@ export type Info = { free_storage: number; total_storage: number; free_ram: numb
 
 export type FileInfo = { path: string; isDir: boolean; date: number; size: number };
 
-export type Progress = { remaining: number; total: number };
+export type Progress = { operationId: number; kind: string; path?: string; remaining: number; total: number; bytesPerSec: number; etaSecs?: number; batchIndex?: number; batchLen?: number };
+
+export type OsImage = { extension: string; model: 'Classic' | 'Cx' | 'CxII'; cas: boolean; teacher: boolean; version?: Version; size: number };
+
+export type OsCandidate = { path: string; image: OsImage };
+
+export type InstallPhase = 'sending' | 'installing' | 'rebooting' | 'verified' | 'failed';
+
+export type Install = { phase: InstallPhase; message?: string };
+
+export type BatchResult<T = null> = { path: string; result?: T; error?: string };
 
 export type PartialCmd = { action: 'download'; path: [string, number]; dest: string }
+    | { action: 'downloadMany'; paths: [string, number][]; dest: string }
+    | { action: 'downloadZip'; paths: string[]; dest: string }
+    | { action: 'deleteMany'; paths: string[] }
     | { action: 'upload'; path: string; src: string }
     | { action: 'uploadOs'; src: string }
     | { action: 'deleteFile'; path: string }
@@ -52,18 +65,38 @@ export type PartialCmd = { action: 'download'; path: [string, number]; dest: str
 
 export type Cmd = { id: number } & PartialCmd;
 
-export type Device = { name: string; isCxIi: boolean; needsDrivers: boolean; info?: Info; progress?: Progress; queue?: Cmd[]; running?: boolean };
+export type Device = { name: string; isCxIi: boolean; needsDrivers: boolean; needsOs?: boolean; info?: Info; progress?: Progress; queue?: Cmd[]; running?: boolean; error?: string; install?: Install };
 
 async function downloadFile(dev: DevId | string, path: [string, number], dest: string) {
     if (typeof dev === 'string') dev = stringToDev(dev);
     await invoke('download_file', {...dev, path, dest});
 }
 
+async function downloadMany(dev: DevId | string, paths: [string, number][], dest: string) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    return await invoke('download_many', {...dev, paths, dest}) as BatchResult<unknown>[];
+}
+
+async function downloadZip(dev: DevId | string, paths: string[], dest: string) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    await invoke('download_zip', {...dev, paths, dest});
+}
+
 async function uploadFile(dev: DevId | string, path: string, src: string) {
     if (typeof dev === 'string') dev = stringToDev(dev);
     await invoke('upload_file', {...dev, path, src});
 }
 
+async function checkUploadSpace(dev: DevId | string, path: string, srcs: string[]) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    return await invoke('check_upload_space', {...dev, path, srcs}) as Info;
+}
+
+async function findOsImages(dev: DevId | string, dir: string) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    return await invoke('find_os_images', {...dev, dir}) as OsCandidate[];
+}
+
 async function uploadOs(dev: DevId | string, src: string) {
     if (typeof dev === 'string') dev = stringToDev(dev);
     await invoke('upload_os', {...dev, src});
@@ -79,6 +112,11 @@ async function deleteDir(dev: DevId | string, path: string) {
     await invoke('delete_dir', {...dev, path});
 }
 
+async function deleteMany(dev: DevId | string, paths: string[]) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    return await invoke('delete_many', {...dev, paths}) as BatchResult[];
+}
+
 async function createDir(dev: DevId | string, path: string) {
     if (typeof dev === 'string') dev = stringToDev(dev);
     await invoke('create_nspire_dir', {...dev, path});
@@ -99,22 +137,29 @@ async function listDir(dev: DevId | string, path: string) {
     return await invoke('list_dir', {...dev, path}) as FileInfo[];
 }
 
+async function listTree(dev: DevId | string, path: string) {
+    if (typeof dev === 'string') dev = stringToDev(dev);
+    return await invoke('list_tree', {...dev, path}) as FileInfo[];
+}
+
 async function listAll(dev: DevId | string, path: FileInfo): Promise<FileInfo[]> {
     if (!path.isDir) return [path];
     try {
-        const contents = await listDir(dev, path.path);
-        const parts: FileInfo[] = [];
-        for (const file of contents) {
-            parts.push(...(await listAll(dev, {...file, path: `${path.path}/${file.path}`})));
-        }
-        parts.push(path);
-        return parts;
+        // list_tree puts folders before their contents, and callers expect
+        // the contents first so that folders can be deleted as they come
+        return [...(await listTree(dev, path.path)).reverse(), path];
     } catch (e) {
         console.error(path, e);
         return [];
     }
 }
 
+function logFailures(results: BatchResult<unknown>[]) {
+    for (const result of results) {
+        if (result.error) console.error(result.path, result.error);
+    }
+}
+
 let queueId = 0;
 
 @Component
@@ -139,6 +184,12 @@ class Devices extends Vue implements GenericDevices {
             const str = devToString(payload);
             this.$set(this.devices[str], 'progress', payload);
         });
+        listen('osInstall', dev => {
+            const {phase, message, ...payload} = dev.payload as Install & DevId;
+            // The calculator may already have left the bus to restart
+            const device = this.devices[devToString(payload)];
+            if (device) this.$set(device, 'install', {phase, message});
+        });
     }
 
     async runQueue(dev: DevId | string) {
@@ -159,6 +210,10 @@ class Devices extends Vue implements GenericDevices {
             try {
                 if (cmd.action === 'download') {
                     await downloadFile(dev, cmd.path, cmd.dest);
+                } else if (cmd.action === 'downloadMany') {
+                    logFailures(await downloadMany(dev, cmd.paths, cmd.dest));
+                } else if (cmd.action === 'downloadZip') {
+                    await downloadZip(dev, cmd.paths, cmd.dest);
                 } else if (cmd.action === 'upload') {
                     await uploadFile(dev, cmd.path, cmd.src);
                 } else if (cmd.action === 'uploadOs') {
@@ -167,6 +222,8 @@ class Devices extends Vue implements GenericDevices {
                     await deleteFile(dev, cmd.path);
                 } else if (cmd.action === 'deleteDir') {
                     await deleteDir(dev, cmd.path);
+                } else if (cmd.action === 'deleteMany') {
+                    logFailures(await deleteMany(dev, cmd.paths));
                 } else if (cmd.action === 'createDir') {
                     await createDir(dev, cmd.path);
                 } else if (cmd.action === 'move') {
@@ -176,6 +233,8 @@ class Devices extends Vue implements GenericDevices {
                 }
             } catch (e) {
                 console.error(e);
+                // The OS checks refuse an image with a reason the user needs to see
+                if (cmd.action === 'uploadOs') this.$set(device, 'error', `${e}`);
             }
             if ('progress' in device) this.$delete(device, 'progress');
             device.queue.shift();
@@ -206,8 +265,9 @@ class Devices extends Vue implements GenericDevices {
 
     async open(dev: DevId | string) {
         if (typeof dev === 'string') dev = stringToDev(dev);
-        const info = await invoke('open_device', {...dev});
+        const info = await invoke('open_device', {...dev}) as Info;
         this.$set(this.devices[devToString(dev)], 'info', info);
+        this.$set(this.devices[devToString(dev)], 'needsOs', info.run_level === 'Recovery');
     }
 
     async close(dev: DevId | string) {
@@ -218,17 +278,31 @@ class Devices extends Vue implements GenericDevices {
 
     async update(dev: DevId | string) {
         if (typeof dev === 'string') dev = stringToDev(dev);
-        const info = await invoke('update_device', {...dev});
+        const info = await invoke('update_device', {...dev}) as Info;
         this.$set(this.devices[devToString(dev)], 'info', info);
+        this.$set(this.devices[devToString(dev)], 'needsOs', info.run_level === 'Recovery');
     }
 
     async listDir(dev: DevId | string, path: string) {
         return await listDir(dev, path);
     }
 
+    async listAll(dev: DevId | string, path: FileInfo) {
+        return await listAll(dev, path);
+    }
+
     async promptUploadFiles(dev: DevId | string, path: string) {
         if (typeof dev !== 'string') dev = devToString(dev);
         const files = await openDialog({filters:[{extensions:['tns'], name:'TNS files'}], multiple: true});
+        if (!files?.length) return;
+        // Refuse the whole batch up front rather than running out of space partway through
+        try {
+            this.$set(this.devices[dev], 'info', await checkUploadSpace(dev, path, files as string[]));
+        } catch (e) {
+            this.$set(this.devices[dev], 'error', `${e}`);
+            return;
+        }
+        this.$delete(this.devices[dev], 'error');
         for (const src of files) {
             this.addToQueue(dev, {action: 'upload', path, src});
         }
@@ -241,24 +315,36 @@ class Devices extends Vue implements GenericDevices {
         this.addToQueue(dev, {action: 'uploadOs', src});
     }
 
+    /** Lets the user pick a folder and lists the OS images in it that fit the calculator, newest first. */
+    async promptFindOsImages(dev: DevId | string) {
+        const dir = await openDialog({directory: true}) as string | null;
+        if (!dir) return null;
+        return await findOsImages(dev, dir);
+    }
+
+    async installOs(dev: DevId | string, src: string) {
+        if (typeof dev !== 'string') dev = devToString(dev);
+        this.$delete(this.devices[dev], 'install');
+        this.addToQueue(dev, {action: 'uploadOs', src});
+    }
+
     async downloadFiles(dev: DevId | string, files: [string, number][]) {
         if (typeof dev !== 'string') dev = devToString(dev);
         const dest = await openDialog({directory: true}) as string | null;
         if (!dest) return;
-        for (const path of files) {
-            this.addToQueue(dev, {action: 'download', path, dest});
-        }
+        this.addToQueue(dev, {action: 'downloadMany', paths: files, dest});
+    }
+
+    async downloadZip(dev: DevId | string, paths: string[]) {
+        if (typeof dev !== 'string') dev = devToString(dev);
+        const dest = await saveDialog({filters:[{extensions:['zip'], name:'Zip archives'}]}) as string | null;
+        if (!dest) return;
+        this.addToQueue(dev, {action: 'downloadZip', paths, dest});
     }
 
     async delete(dev: DevId | string, files: FileInfo[]) {
         if (typeof dev !== 'string') dev = devToString(dev);
-        const toDelete: FileInfo[] = [];
-        for (const file of files) {
-            toDelete.push(...await listAll(dev, file));
-        }
-        for (const file of toDelete) {
-            this.addToQueue(dev, {action: file.isDir ? 'deleteDir' : 'deleteFile', path: file.path});
-        }
+        this.addToQueue(dev, {action: 'deleteMany', paths: files.map(file => file.path)});
     }
 
     async createDir(dev: DevId | string, path: string) {
@@ -53,6 +53,7 @@ export interface GenericDevices {
     uploadOs(dev: DevId | string, filter: string): Promise<void>;
     uploadOsFile(dev: DevId | string, file: File): Promise<void>;
     downloadFiles(dev: DevId | string, files: [string, number][]): Promise<void>;
+    downloadZip?(dev: DevId | string, paths: string[]): Promise<void>;
     delete(dev: DevId | string, files: FileInfo[]): Promise<void>;
     createDir(dev: DevId | string, path: string): Promise<void>;
     copy(dev: DevId | string, src: string, dest: string): Promise<void>;